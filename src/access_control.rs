@@ -0,0 +1,61 @@
+use heapless::Vec;
+use smoltcp::wire::EthernetAddress;
+
+/// the most entries each of the allow and deny lists can hold
+pub const MAX_ACCESS_LIST_LEN: usize = 32;
+
+/// a hardware address, or all of the addresses from one manufacturer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HardwareAddressMatch {
+    Address(EthernetAddress),
+    /// the first three bytes of the address, which say who made the device
+    Oui([u8; 3]),
+}
+
+impl HardwareAddressMatch {
+    pub fn matches(&self, address: EthernetAddress) -> bool {
+        match self {
+            Self::Address(a) => *a == address,
+            Self::Oui(oui) => address.as_bytes().starts_with(oui),
+        }
+    }
+}
+
+/// The settings for which clients the server gives addresses to
+pub struct AccessConfig<'c> {
+    /// If this isn't empty, only clients on it, or with a reservation, are given addresses.
+    pub allow_list: &'c [HardwareAddressMatch],
+    /// clients on this are never given addresses, even if they're on the allow list
+    pub deny_list: &'c [HardwareAddressMatch],
+    /// Stay silent for clients that aren't allowed an address. Otherwise their requests are
+    /// answered with a nak, so they stop asking for the address they have.
+    pub ignore_unknown_clients: bool,
+}
+
+/// A copy of an `AccessConfig` that the server keeps.
+pub struct AccessControl {
+    allow_list: Vec<HardwareAddressMatch, MAX_ACCESS_LIST_LEN>,
+    deny_list: Vec<HardwareAddressMatch, MAX_ACCESS_LIST_LEN>,
+    pub ignore_unknown_clients: bool,
+}
+
+impl AccessControl {
+    /// Returns None if either list is too long.
+    pub fn new(config: &AccessConfig<'_>) -> Option<Self> {
+        Some(Self {
+            allow_list: Vec::from_slice(config.allow_list).ok()?,
+            deny_list: Vec::from_slice(config.deny_list).ok()?,
+            ignore_unknown_clients: config.ignore_unknown_clients,
+        })
+    }
+
+    /// whether the client with this hardware address can be given an address
+    pub fn is_allowed(&self, address: EthernetAddress, has_reservation: bool) -> bool {
+        if self.deny_list.iter().any(|m| m.matches(address)) {
+            return false;
+        }
+        self.allow_list.is_empty()
+            || has_reservation
+            || self.allow_list.iter().any(|m| m.matches(address))
+    }
+}
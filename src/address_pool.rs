@@ -0,0 +1,81 @@
+use smoltcp::wire::Ipv4Address;
+
+/// A range of addresses inside a subnet that the dhcp server can hand out.
+/// Every address is checked against the subnet, so addresses from other networks
+/// never map onto an index in the pool.
+#[derive(Debug, Clone, Copy)]
+pub struct AddressPool {
+    network: u32,
+    prefix_len: u8,
+    start: u32,
+    end: u32,
+}
+
+impl AddressPool {
+    /// Create a pool handing out `start..=end` from the subnet `network/prefix_len`.
+    /// Returns None if the network has host bits set, or the range isn't made of host
+    /// addresses from that subnet.
+    pub fn new(
+        network: Ipv4Address,
+        prefix_len: u8,
+        start: Ipv4Address,
+        end: Ipv4Address,
+    ) -> Option<Self> {
+        // anything smaller than a /30 has no room for a server and a client
+        if prefix_len > 30 {
+            return None;
+        }
+        let pool = Self {
+            network: u32::from_be_bytes(network.0),
+            prefix_len,
+            start: u32::from_be_bytes(start.0),
+            end: u32::from_be_bytes(end.0),
+        };
+        let valid = (pool.network & !pool.mask()) == 0
+            && pool.start <= pool.end
+            && pool.is_host(pool.start)
+            && pool.is_host(pool.end);
+        valid.then_some(pool)
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0)
+    }
+
+    /// whether the address is in the subnet, and is neither the network nor the
+    /// broadcast address
+    fn is_host(&self, address: u32) -> bool {
+        let mask = self.mask();
+        (address & mask) == self.network && (address & !mask) != 0 && (address & !mask) != !mask
+    }
+
+    pub fn subnet_mask(&self) -> Ipv4Address {
+        Ipv4Address::from_bytes(&self.mask().to_be_bytes())
+    }
+
+    /// whether the address is a host on this pool's subnet
+    pub fn contains(&self, address: Ipv4Address) -> bool {
+        self.is_host(u32::from_be_bytes(address.0))
+    }
+
+    /// the number of addresses in the pool
+    pub fn size(&self) -> usize {
+        (self.end - self.start) as usize + 1
+    }
+
+    /// the address at `index` in the pool
+    pub fn address(&self, index: usize) -> Option<Ipv4Address> {
+        (index < self.size())
+            .then(|| Ipv4Address::from_bytes(&(self.start + index as u32).to_be_bytes()))
+    }
+
+    /// the index of an address in the pool, if the address is in the pool's range
+    pub fn index(&self, address: Ipv4Address) -> Option<usize> {
+        let address = u32::from_be_bytes(address.0);
+        (self.start..=self.end)
+            .contains(&address)
+            .then(|| (address - self.start) as usize)
+    }
+}
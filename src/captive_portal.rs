@@ -0,0 +1,55 @@
+use core::fmt::Write;
+
+use embassy_time::Instant;
+use heapless::String;
+use smoltcp::wire::Ipv4Address;
+
+use pico_dhcp_dns_server::lease_table;
+
+/// the path of the captive portal api from RFC 8908
+pub const API_PATH: &str = "/api/captive";
+
+/// the longest url of the user portal
+pub const MAX_URL_LEN: usize = 64;
+
+/// the media type of the api's responses
+pub const API_CONTENT_TYPE: &str = "application/captive+json";
+
+/// the longest response the api gives
+pub const MAX_API_RESPONSE_LEN: usize = 128;
+
+/// the page clients are sent to on the server at `server_address`, which is where every other
+/// request gets redirected
+pub fn user_portal_url(server_address: Ipv4Address) -> String<MAX_URL_LEN> {
+    let mut url = String::new();
+    let _ = write!(url, "http://{}/", server_address);
+    url
+}
+
+/// how long the lease on this address has left, if it has one
+fn seconds_remaining(address: Ipv4Address) -> Option<u64> {
+    lease_table::find_by_address(address)?
+        .lease_end
+        .checked_duration_since(Instant::now())
+        .map(|d| d.as_secs())
+}
+
+/// The json the api on the server at `server_address` answers a client with. Clients are
+/// always captive, and the time left is only included if the client's address is known and
+/// has a lease.
+pub fn api_response(
+    client: Option<Ipv4Address>,
+    server_address: Ipv4Address,
+) -> String<MAX_API_RESPONSE_LEN> {
+    let mut response = String::new();
+    let _ = write!(
+        response,
+        "{{\"captive\":true,\"user-portal-url\":\"{}\"",
+        user_portal_url(server_address)
+    );
+    if let Some(seconds) = client.and_then(seconds_remaining) {
+        let _ = write!(response, ",\"seconds-remaining\":{}", seconds);
+    }
+    let _ = response.push('}');
+    response
+}
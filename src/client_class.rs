@@ -0,0 +1,101 @@
+use embassy_time::Duration;
+use heapless::Vec;
+use smoltcp::wire::{DhcpOption, EthernetAddress};
+
+/// the longest value a class can be matched on
+pub const MAX_CLASS_MATCH_LEN: usize = 32;
+
+/// what puts a client in a class
+#[derive(Debug, Clone, Copy)]
+pub enum ClassMatch<'c> {
+    /// the vendor class identifier (option 60) starts with these bytes, like `b"MSFT"`
+    VendorClass(&'c [u8]),
+    /// one of the user classes the client sent (option 77) is exactly this
+    UserClass(&'c [u8]),
+    /// the hardware address starts with these bytes, usually a three byte OUI
+    HardwareAddressPrefix(&'c [u8]),
+}
+
+/// A family of devices that are given their own configuration.
+/// To give a class its own pool, set the pool's `client_class` to the class's name.
+pub struct ClientClass<'c> {
+    pub name: &'c str,
+    pub matches: ClassMatch<'c>,
+    /// the lease time for the class, instead of the pool's
+    pub lease_time: Option<Duration>,
+    /// Options given to the class, which take the place of the pool's options with the same
+    /// code. This is where the vendor specific information option (43) goes.
+    pub options: &'c [DhcpOption<'c>],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchKind {
+    VendorClass,
+    UserClass,
+    HardwareAddressPrefix,
+}
+
+/// A copy of a `ClassMatch` that the server keeps.
+#[derive(Debug, Clone)]
+pub struct ClassMatcher {
+    kind: MatchKind,
+    value: Vec<u8, MAX_CLASS_MATCH_LEN>,
+}
+
+impl ClassMatcher {
+    /// Returns None if the value to match is empty or too long.
+    pub fn new(class_match: ClassMatch<'_>) -> Option<Self> {
+        let (kind, value) = match class_match {
+            ClassMatch::VendorClass(value) => (MatchKind::VendorClass, value),
+            ClassMatch::UserClass(value) => (MatchKind::UserClass, value),
+            ClassMatch::HardwareAddressPrefix(value) => (MatchKind::HardwareAddressPrefix, value),
+        };
+        if value.is_empty() {
+            return None;
+        }
+        Some(Self {
+            kind,
+            value: Vec::from_slice(value).ok()?,
+        })
+    }
+
+    /// whether a client that sent these is in the class
+    pub fn matches(
+        &self,
+        vendor_class: Option<&[u8]>,
+        user_class: Option<&[u8]>,
+        hardware_address: EthernetAddress,
+    ) -> bool {
+        match self.kind {
+            MatchKind::VendorClass => vendor_class.is_some_and(|v| v.starts_with(&self.value)),
+            MatchKind::UserClass => {
+                user_class.is_some_and(|u| user_classes(u).any(|class| class == self.value))
+            }
+            MatchKind::HardwareAddressPrefix => {
+                hardware_address.as_bytes().starts_with(&self.value)
+            }
+        }
+    }
+}
+
+/// The classes in a user class option. RFC 3004 gives each one a length byte, but some
+/// clients send a single class without one, so if the lengths don't add up, the whole
+/// option is taken as one class.
+fn user_classes(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = data;
+    let mut valid = !data.is_empty();
+    while valid && !rest.is_empty() {
+        let len = rest[0] as usize;
+        valid = len > 0 && rest.len() > len;
+        rest = rest.get(1 + len..).unwrap_or(&[]);
+    }
+    let mut items = if valid { data } else { &[][..] };
+    let whole = (!valid).then_some(data);
+    core::iter::from_fn(move || {
+        let (&len, rest) = items.split_first()?;
+        let (class, rest) = rest.split_at(len as usize);
+        items = rest;
+        Some(class)
+    })
+    .chain(whole)
+}
@@ -0,0 +1,81 @@
+use heapless::Vec;
+
+use crate::host_table::{sanitize, Hostname};
+
+/// the server should put the client's address record in dns (S)
+pub const FLAG_SERVER_UPDATE: u8 = 0x01;
+/// the server went against what the client asked for in the S flag (O)
+pub const FLAG_OVERRIDE: u8 = 0x02;
+/// the name is in dns wire format rather than ascii (E)
+pub const FLAG_ENCODED: u8 = 0x04;
+/// the server shouldn't make any dns updates (N)
+pub const FLAG_NO_UPDATE: u8 = 0x08;
+
+/// the flags and rcodes, followed by the longest name dns allows
+pub const MAX_CLIENT_FQDN_LEN: usize = 3 + 255;
+
+/// The client fqdn option (81) from RFC 4702, where a client sends its name and says who
+/// should put it in dns.
+#[derive(Debug, Clone)]
+pub struct ClientFqdn {
+    flags: u8,
+    /// the first label of the name the client sent, made into a valid hostname
+    pub hostname: Option<Hostname>,
+}
+
+impl ClientFqdn {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (&flags, rest) = data.split_first()?;
+        // the two rcode bytes are deprecated, so they're skipped
+        let name = rest.get(2..)?;
+        let label = if flags & FLAG_ENCODED != 0 {
+            name.split_first()
+                .and_then(|(&len, labels)| labels.get(..len as usize))
+        } else {
+            Some(name)
+        };
+        Some(Self {
+            flags,
+            hostname: label.and_then(sanitize),
+        })
+    }
+
+    /// whether the client lets the server put its name in dns
+    pub fn allows_updates(&self) -> bool {
+        self.flags & FLAG_NO_UPDATE == 0
+    }
+
+    /// The option to send back to the client, telling it the name the server put in dns under
+    /// `domain`, or that the server didn't put any name in dns if `hostname` is None.
+    /// The name is encoded the same way the client encoded its own.
+    pub fn reply(&self, hostname: Option<&Hostname>, domain: &str) -> Vec<u8, MAX_CLIENT_FQDN_LEN> {
+        let encoded = self.flags & FLAG_ENCODED != 0;
+        let hostname = hostname.filter(|_| self.allows_updates());
+        let flags = match hostname {
+            None => FLAG_NO_UPDATE,
+            // the client wanted to do the update itself, but the server does it instead
+            Some(_) if self.flags & FLAG_SERVER_UPDATE == 0 => FLAG_SERVER_UPDATE | FLAG_OVERRIDE,
+            Some(_) => FLAG_SERVER_UPDATE,
+        };
+        let mut option = Vec::new();
+        // servers always send 255 in both rcodes
+        let _ = option.extend_from_slice(&[flags | (self.flags & FLAG_ENCODED), 255, 255]);
+        if let Some(hostname) = hostname {
+            for (i, label) in core::iter::once(hostname.as_str())
+                .chain(domain.split('.'))
+                .enumerate()
+            {
+                if encoded {
+                    let _ = option.push(label.len() as u8);
+                } else if i > 0 {
+                    let _ = option.push(b'.');
+                }
+                let _ = option.extend_from_slice(label.as_bytes());
+            }
+            if encoded {
+                let _ = option.push(0);
+            }
+        }
+        option
+    }
+}
@@ -0,0 +1,61 @@
+use heapless::Vec;
+use smoltcp::wire::EthernetAddress;
+
+use crate::lease_storage::MAX_IDENTIFIER_LEN;
+
+/// the longest client identifier that is kept, which is as long as one that can be stored in flash
+pub const MAX_CLIENT_ID_LEN: usize = MAX_IDENTIFIER_LEN;
+
+/// how much of a longer identifier is kept in front of its hash
+const PREFIX_LEN: usize = MAX_CLIENT_ID_LEN - 4;
+
+/// the hardware type for ethernet, which is also used by wifi
+const HARDWARE_TYPE_ETHERNET: u8 = 1;
+
+/// What a client is known by: the raw bytes of its client identifier option (61), or if it
+/// didn't send one, its hardware type and address in the same form.
+/// This covers identifiers made from a DUID (RFC 4361) as well as hardware addresses.
+/// Identifiers longer than `MAX_CLIENT_ID_LEN`, such as long DUIDs, are kept as their start
+/// followed by a hash of the whole identifier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientId(Vec<u8, MAX_CLIENT_ID_LEN>);
+
+impl ClientId {
+    /// Use the bytes of a client identifier option. Returns None if the identifier is empty.
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        if bytes.is_empty() {
+            return None;
+        }
+        if let Ok(bytes) = Vec::from_slice(bytes) {
+            return Some(Self(bytes));
+        }
+        let mut key = Vec::new();
+        let _ = key.extend_from_slice(&bytes[..PREFIX_LEN]);
+        let _ = key.extend_from_slice(&fnv1a(bytes).to_be_bytes());
+        Some(Self(key))
+    }
+
+    /// the identifier for a client that didn't send one, which is the same as the one a
+    /// client using its hardware address as its identifier would send
+    pub fn from_hardware_address(address: EthernetAddress) -> Self {
+        let mut bytes = Vec::new();
+        let _ = bytes.push(HARDWARE_TYPE_ETHERNET);
+        let _ = bytes.extend_from_slice(address.as_bytes());
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// A hash of the identifier, which is the same every time the server starts (FNV-1a).
+    pub fn stable_hash(&self) -> u32 {
+        fnv1a(&self.0)
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(0x0100_0193)
+    })
+}
@@ -0,0 +1,89 @@
+use embassy_net::raw::RawSocket;
+use embassy_time::{with_timeout, Duration};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr};
+
+/// the identifier on the server's echo requests, so replies to them can be told apart
+const ECHO_IDENT: u16 = 0x7064;
+
+const ECHO_DATA: &[u8] = b"dhcp conflict probe";
+
+/// big enough for an echo request or reply with the probe's data, along with the ip header
+const PROBE_BUFFER_LEN: usize = 128;
+
+/// Checks whether something is already using an address by pinging it.
+/// embassy-net doesn't give access to arp, so a device that ignores pings won't be found.
+pub struct ConflictProbe<'a> {
+    /// a raw ipv4 socket for icmp
+    socket: RawSocket<'a>,
+    seq_no: u16,
+}
+
+impl<'a> ConflictProbe<'a> {
+    pub fn new(socket: RawSocket<'a>) -> Self {
+        Self { socket, seq_no: 0 }
+    }
+
+    /// Send an echo request from `source` to `address` and wait up to `timeout` for the reply.
+    /// Returns whether anything answered.
+    pub async fn is_in_use(
+        &mut self,
+        source: Ipv4Address,
+        address: Ipv4Address,
+        timeout: Duration,
+    ) -> bool {
+        self.seq_no = self.seq_no.wrapping_add(1);
+        let seq_no = self.seq_no;
+        let checksum = ChecksumCapabilities::default();
+
+        let icmp_repr = Icmpv4Repr::EchoRequest {
+            ident: ECHO_IDENT,
+            seq_no,
+            data: ECHO_DATA,
+        };
+        let ip_repr = Ipv4Repr {
+            src_addr: source,
+            dst_addr: address,
+            next_header: IpProtocol::Icmp,
+            payload_len: icmp_repr.buffer_len(),
+            hop_limit: 64,
+        };
+        let mut buffer = [0; PROBE_BUFFER_LEN];
+        let len = ip_repr.buffer_len() + icmp_repr.buffer_len();
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut buffer[..len]);
+        ip_repr.emit(&mut ip_packet, &checksum);
+        icmp_repr.emit(
+            &mut Icmpv4Packet::new_unchecked(ip_packet.payload_mut()),
+            &checksum,
+        );
+        self.socket.send(&buffer[..len]).await;
+
+        let reply = async {
+            loop {
+                let Ok(len) = self.socket.recv(&mut buffer).await else {
+                    continue;
+                };
+                let Ok(ip_packet) = Ipv4Packet::new_checked(&buffer[..len]) else {
+                    continue;
+                };
+                if ip_packet.src_addr() != address {
+                    continue;
+                }
+                let Ok(icmp_packet) = Icmpv4Packet::new_checked(ip_packet.payload()) else {
+                    continue;
+                };
+                if let Ok(Icmpv4Repr::EchoReply {
+                    ident: ECHO_IDENT,
+                    seq_no: reply_seq_no,
+                    ..
+                }) = Icmpv4Repr::parse(&icmp_packet, &checksum)
+                {
+                    if reply_seq_no == seq_no {
+                        break;
+                    }
+                }
+            }
+        };
+        with_timeout(timeout, reply).await.is_ok()
+    }
+}
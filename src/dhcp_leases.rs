@@ -0,0 +1,2168 @@
+use core::fmt::Write;
+use core::ops::Range;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_time::{Duration, Instant};
+use heapless::{String, Vec};
+use smoltcp::wire::{
+    DhcpMessageType, DhcpOption, DhcpPacket, DhcpRepr, Error, EthernetAddress, IpEndpoint,
+    Ipv4Address, Ipv4Cidr, Result,
+};
+
+use crate::access_control::{AccessConfig, AccessControl};
+use crate::address_pool::AddressPool;
+use crate::client_class::{ClassMatcher, ClientClass};
+use crate::client_fqdn::ClientFqdn;
+use crate::client_id::ClientId;
+use crate::dhcp_options::{
+    encode_addresses, encode_static_routes, OptionRegistry, ReplyOptions, RequestedOptions,
+    StaticRoute, DHCP_OPT_BOOTFILE_NAME, DHCP_OPT_CAPTIVE_PORTAL, DHCP_OPT_CLASSLESS_STATIC_ROUTE,
+    DHCP_OPT_CLIENT_FQDN, DHCP_OPT_CLIENT_IDENTIFIER, DHCP_OPT_DOMAIN_NAME_SERVER,
+    DHCP_OPT_HOST_NAME, DHCP_OPT_REBINDING_TIME, DHCP_OPT_RELAY_AGENT_INFORMATION,
+    DHCP_OPT_RENEWAL_TIME, DHCP_OPT_ROUTER, DHCP_OPT_SUBNET_MASK, DHCP_OPT_TFTP_SERVER_NAME,
+    DHCP_OPT_USER_CLASS, DHCP_OPT_VENDOR_CLASS_IDENTIFIER, IP_UDP_HEADER_LEN, MAX_OPTION_LEN,
+};
+use crate::host_table::{deduplicate, sanitize, Hostname};
+use crate::lease_storage::StoredLease;
+use crate::lease_table::{self, LeaseInfo};
+use crate::rate_limit::RateLimit;
+
+/// the default fraction of the lease time after which a client renews (T1), from RFC 2131
+pub const DEFAULT_RENEW_RATIO: f32 = 0.5;
+
+/// the default fraction of the lease time after which a client rebinds (T2), from RFC 2131
+pub const DEFAULT_REBIND_RATIO: f32 = 0.875;
+
+/// the number of events that can be waiting to be read before the oldest ones are dropped
+const EVENT_QUEUE_LEN: usize = 8;
+
+/// the most tasks that can be subscribed to the events at once
+pub const MAX_EVENT_SUBSCRIBERS: usize = 4;
+
+/// Changes to the leases that the rest of the firmware might want to know about, which tasks
+/// can get with `DHCP_EVENTS.subscriber()`. The server never waits on this, so a subscriber
+/// that falls behind misses the oldest events, and is told how many it missed.
+pub static DHCP_EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    DhcpEvent,
+    EVENT_QUEUE_LEN,
+    MAX_EVENT_SUBSCRIBERS,
+    1,
+> = PubSubChannel::new();
+
+fn publish_event(event: DhcpEvent) {
+    DHCP_EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// something that happened to an address in the pool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DhcpEvent {
+    /// a client was given a lease on an address it didn't have a lease on
+    LeaseAssigned(LeaseInfo),
+    /// a client extended the lease it already had
+    LeaseRenewed(LeaseInfo),
+    /// a client gave its lease back, so the address is free again
+    LeaseReleased(LeaseInfo),
+    /// a lease ran out without being renewed, so the address is free again
+    LeaseExpired(LeaseInfo),
+    /// a client found the address it was leased in use by something else, so the address is
+    /// held off
+    LeaseDeclined(LeaseInfo),
+    /// an offer was never taken up by the client, so the address is free again
+    OfferExpired {
+        address: Ipv4Address,
+        identifier: ClientId,
+    },
+}
+
+/// the maximum number of static reservations the server can hold
+const MAX_RESERVATIONS: usize = 16;
+
+/// the maximum number of pools the server can hand out addresses from
+const MAX_POOLS: usize = 4;
+
+/// the maximum number of client classes the server can tell apart
+const MAX_CLASSES: usize = 8;
+
+/// the number of clients whose last address is remembered
+const MAX_REMEMBERED_CLIENTS: usize = 32;
+
+/// what a reservation is matched against in a client's messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationKey {
+    /// the `chaddr` field of the packet
+    HardwareAddress(EthernetAddress),
+    /// the raw bytes of the client identifier option
+    ClientIdentifier(&'static [u8]),
+}
+
+/// a fixed address for a single client
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub key: ReservationKey,
+    pub address: Ipv4Address,
+}
+
+impl Reservation {
+    fn matches(
+        &self,
+        client_hardware_address: EthernetAddress,
+        client_identifier: Option<&ClientId>,
+    ) -> bool {
+        match self.key {
+            ReservationKey::HardwareAddress(address) => address == client_hardware_address,
+            ReservationKey::ClientIdentifier(identifier) => {
+                client_identifier.is_some_and(|c| ClientId::new(identifier).as_ref() == Some(c))
+            }
+        }
+    }
+
+    /// whether the reservation is for the client whose leases are kept under this identifier
+    fn is_for(&self, identifier: &ClientId) -> bool {
+        match self.key {
+            ReservationKey::HardwareAddress(address) => {
+                ClientId::from_hardware_address(address) == *identifier
+            }
+            ReservationKey::ClientIdentifier(bytes) => {
+                ClientId::new(bytes).as_ref() == Some(identifier)
+            }
+        }
+    }
+}
+
+/// the longest boot file name, which has to fit in the `file` field with a zero after it
+const MAX_BOOT_FILE_LEN: usize = 127;
+
+/// Where a client that boots over the network gets its boot file from
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkBoot<'c> {
+    /// the tftp server, which goes in `siaddr` and the tftp server name option (66)
+    pub server: Ipv4Address,
+    /// the boot file, which goes in the `file` field and the bootfile name option (67)
+    pub file: &'c str,
+}
+
+/// A range of addresses to hand out, and the configuration that goes with them
+pub struct PoolConfig<'c> {
+    pub pool: AddressPool,
+    pub lease_time: Duration,
+    /// The routers clients should use, in order of preference. Leave this empty for a pool
+    /// without a default route, so that its clients keep using the one from their other uplink.
+    pub routers: &'c [Ipv4Address],
+    /// Routes to particular networks, given out in the classless static route option (121).
+    /// Clients that take this option ignore the router option, so if the pool has routers, a
+    /// default route through the first one is added after these.
+    pub static_routes: &'c [StaticRoute],
+    pub dns_server: Ipv4Address,
+    /// if set, the pool is only used for clients in the client class with this name
+    pub client_class: Option<&'c str>,
+    /// options given out on top of the subnet mask, routers, static routes and dns server
+    pub options: &'c [DhcpOption<'c>],
+    pub network_boot: Option<NetworkBoot<'c>>,
+}
+
+/// The settings the dhcp server is started with
+pub struct DhcpConfig<'c> {
+    /// the address of the server on the interface it listens on
+    pub server_address: Ipv4Address,
+    /// The pools to hand out addresses from. A client gets an address from the first pool
+    /// that is on its subnet and that it is in the class of: clients on the local network are
+    /// on the interface's subnet, and clients behind a relay agent are on the relay agent's.
+    /// A server listens on a single interface, so another interface gets a server of its own
+    /// with the pools for that interface's subnet.
+    pub pools: &'c [PoolConfig<'c>],
+    /// The classes clients are sorted into. A client is in the first class it matches.
+    pub classes: &'c [ClientClass<'c>],
+    /// how long an address is kept for a client after it has been offered
+    pub offer_time: Duration,
+    /// the fraction of the lease time after which a client tries to renew with this server (T1)
+    pub renew_ratio: f32,
+    /// the fraction of the lease time after which a client tries to rebind with any server (T2)
+    pub rebind_ratio: f32,
+    /// how long an address that a client declined, or that something answered a probe on, is
+    /// kept out of the pool
+    pub decline_hold_off: Duration,
+    /// how long to wait for an answer when checking that nothing is using an address before
+    /// offering it, or None to offer addresses without checking
+    pub probe_timeout: Option<Duration>,
+    /// how many messages each client can send before the rest are dropped
+    pub client_rate_limit: RateLimit,
+    /// the most messages the server handles in a second, from all clients together
+    pub max_packets_per_second: u32,
+    pub reservations: &'c [Reservation],
+    pub access: AccessConfig<'c>,
+    /// The uri of the captive portal api (RFC 8908), which is given to clients in every pool in
+    /// option 114 (RFC 8910), or None to leave it out. It has to be an https uri.
+    pub captive_portal_api: Option<&'c str>,
+}
+
+/// A pool the server hands out addresses from, along with the configuration for its clients.
+/// The pools share the server's assignment slots, with each one using a run of them.
+struct Pool {
+    addresses: AddressPool,
+    /// the assignment slot of the first address in the pool
+    first_slot: usize,
+    lease_time: Duration,
+    /// the index of the client class the pool is kept for
+    client_class: Option<usize>,
+    options: OptionRegistry,
+    boot_server: Option<Ipv4Address>,
+    /// the boot file name, which is empty if the pool isn't set up for network booting
+    boot_file: Vec<u8, MAX_BOOT_FILE_LEN>,
+}
+
+impl Pool {
+    fn new(
+        config: &PoolConfig<'_>,
+        first_slot: usize,
+        classes: &[ClientClass<'_>],
+    ) -> Option<Self> {
+        let mut options = OptionRegistry::default();
+        options
+            .set(DHCP_OPT_SUBNET_MASK, config.pool.subnet_mask().as_bytes())
+            .ok()?;
+        if !config.routers.is_empty() {
+            options
+                .set(DHCP_OPT_ROUTER, &encode_addresses(config.routers).ok()?)
+                .ok()?;
+        }
+        if !config.static_routes.is_empty() {
+            let default_route = config
+                .routers
+                .first()
+                .filter(|_| !config.static_routes.iter().any(StaticRoute::is_default))
+                .map(|&router| StaticRoute {
+                    destination: Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0),
+                    router,
+                });
+            let routes = encode_static_routes(config.static_routes.iter().chain(&default_route));
+            options
+                .set(DHCP_OPT_CLASSLESS_STATIC_ROUTE, &routes.ok()?)
+                .ok()?;
+        }
+        options
+            .set(DHCP_OPT_DOMAIN_NAME_SERVER, config.dns_server.as_bytes())
+            .ok()?;
+        let mut boot_file = Vec::new();
+        if let Some(network_boot) = config.network_boot {
+            // the server name option can hold an address as well as a name
+            let mut server_name: String<15> = String::new();
+            write!(server_name, "{}", network_boot.server).ok()?;
+            options
+                .set(DHCP_OPT_TFTP_SERVER_NAME, server_name.as_bytes())
+                .ok()?;
+            options
+                .set(DHCP_OPT_BOOTFILE_NAME, network_boot.file.as_bytes())
+                .ok()?;
+            boot_file = Vec::from_slice(network_boot.file.as_bytes()).ok()?;
+        }
+        for option in config.options {
+            options.set(option.kind, option.data).ok()?;
+        }
+        let client_class = match config.client_class {
+            Some(name) => Some(classes.iter().position(|class| class.name == name)?),
+            None => None,
+        };
+        Some(Self {
+            addresses: config.pool,
+            first_slot,
+            lease_time: config.lease_time,
+            client_class,
+            options,
+            boot_server: config.network_boot.map(|network_boot| network_boot.server),
+            boot_file,
+        })
+    }
+
+    /// the assignment slots this pool's addresses use
+    fn slots(&self) -> Range<usize> {
+        self.first_slot..self.first_slot + self.addresses.size()
+    }
+
+    /// whether a client in this class can get an address from the pool
+    fn serves_class(&self, class: Option<usize>) -> bool {
+        match self.client_class {
+            Some(pool_class) => class == Some(pool_class),
+            None => true,
+        }
+    }
+}
+
+/// A client class the server sorts clients into, along with the configuration for its clients.
+struct Class {
+    matcher: ClassMatcher,
+    lease_time: Option<Duration>,
+    options: OptionRegistry,
+}
+
+impl Class {
+    fn new(config: &ClientClass<'_>) -> Option<Self> {
+        let mut options = OptionRegistry::default();
+        for option in config.options {
+            options.set(option.kind, option.data).ok()?;
+        }
+        Some(Self {
+            matcher: ClassMatcher::new(config.matches)?,
+            lease_time: config.lease_time,
+            options,
+        })
+    }
+}
+
+/// The parts of a client's message that the server needs to answer it. These are copied out
+/// of the packet, so the data buffer is free to be reused for the reply.
+pub struct ClientMessage {
+    pub message_type: DhcpMessageType,
+    pub transaction_id: u32,
+    pub client_hardware_address: EthernetAddress,
+    /// the client identifier option, if the client sent one
+    pub client_identifier: Option<ClientId>,
+    pub client_ip: Ipv4Address,
+    pub relay_agent_ip: Ipv4Address,
+    /// the relay agent information option the relay added, which goes back to it unchanged
+    pub relay_agent_information: Option<Vec<u8, MAX_OPTION_LEN>>,
+    pub vendor_class: Option<Vec<u8, MAX_OPTION_LEN>>,
+    pub user_class: Option<Vec<u8, MAX_OPTION_LEN>>,
+    /// the index of the client class the client is in, which the server fills in
+    pub class: Option<usize>,
+    /// whether the client asked for replies to be broadcast
+    pub broadcast: bool,
+    pub requested_ip: Option<Ipv4Address>,
+    pub server_identifier: Option<Ipv4Address>,
+    /// the client fqdn option, if the client sent one
+    pub client_fqdn: Option<ClientFqdn>,
+    /// the hostname the client sent, made into a valid dns label. this comes from the client
+    /// fqdn option if there is one, otherwise from the host name option
+    pub hostname: Option<Hostname>,
+    pub requested_options: RequestedOptions,
+}
+
+impl ClientMessage {
+    pub fn new(
+        packet: &DhcpPacket<&[u8]>,
+        packet_repr: &DhcpRepr<'_>,
+        client_identifier: Option<ClientId>,
+    ) -> Self {
+        // smoltcp doesn't parse these, so they're read from the raw options
+        let client_fqdn = packet
+            .options()
+            .find(|o| o.kind == DHCP_OPT_CLIENT_FQDN)
+            .and_then(|o| ClientFqdn::parse(o.data));
+        let hostname = client_fqdn
+            .as_ref()
+            .and_then(|fqdn| fqdn.hostname.clone())
+            .or_else(|| {
+                packet
+                    .options()
+                    .find(|o| o.kind == DHCP_OPT_HOST_NAME)
+                    .and_then(|o| sanitize(o.data))
+            });
+        Self {
+            message_type: packet_repr.message_type,
+            transaction_id: packet_repr.transaction_id,
+            client_hardware_address: packet_repr.client_hardware_address,
+            client_identifier,
+            client_ip: packet_repr.client_ip,
+            relay_agent_ip: packet_repr.relay_agent_ip,
+            relay_agent_information: packet
+                .options()
+                .find(|o| o.kind == DHCP_OPT_RELAY_AGENT_INFORMATION)
+                .and_then(|o| Vec::from_slice(o.data).ok()),
+            vendor_class: packet
+                .options()
+                .find(|o| o.kind == DHCP_OPT_VENDOR_CLASS_IDENTIFIER)
+                .and_then(|o| Vec::from_slice(o.data).ok()),
+            user_class: packet
+                .options()
+                .find(|o| o.kind == DHCP_OPT_USER_CLASS)
+                .and_then(|o| Vec::from_slice(o.data).ok()),
+            class: None,
+            broadcast: packet_repr.broadcast,
+            requested_ip: packet_repr.requested_ip,
+            server_identifier: packet_repr.server_identifier,
+            client_fqdn,
+            hostname,
+            requested_options: RequestedOptions::new(
+                packet_repr.parameter_request_list,
+                packet_repr.max_size,
+            ),
+        }
+    }
+
+    /// the identifier the client's assignment is kept under: its client identifier if it sent
+    /// one, otherwise its hardware address
+    pub fn identifier(&self) -> ClientId {
+        self.client_identifier
+            .clone()
+            .unwrap_or_else(|| ClientId::from_hardware_address(self.client_hardware_address))
+    }
+}
+
+/// where the options start in a dhcp packet, after the fixed fields and the magic cookie
+const OPTIONS_START: usize = 240;
+
+/// the `file` field of a dhcp packet, which smoltcp always leaves empty
+const BOOT_FILE_FIELD: Range<usize> = 108..236;
+
+/// The client identifier option in a packet, if it has one. An empty identifier is an error
+/// rather than falling back to the hardware address, since clients that share a hardware
+/// address would then share a lease.
+pub fn client_identifier(packet: &DhcpPacket<&[u8]>) -> Result<Option<ClientId>> {
+    match packet
+        .options()
+        .find(|o| o.kind == DHCP_OPT_CLIENT_IDENTIFIER)
+    {
+        Some(option) => ClientId::new(option.data).map(Some).ok_or(Error),
+        None => Ok(None),
+    }
+}
+
+/// Change the code of every `kind` option in the packet's options field to `new_kind`.
+pub fn rename_option(buffer: &mut [u8], kind: u8, new_kind: u8) {
+    let mut offset = OPTIONS_START;
+    while let Some(&code) = buffer.get(offset) {
+        match code {
+            0 => offset += 1,
+            255 => break,
+            _ => {
+                let Some(&len) = buffer.get(offset + 1) else {
+                    break;
+                };
+                if code == kind {
+                    buffer[offset] = new_kind;
+                }
+                offset += 2 + len as usize;
+            }
+        }
+    }
+}
+
+/// where a reply to a client should be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyDestination {
+    /// to the relay agent that forwarded the client's message, on the server port
+    RelayAgent(Ipv4Address),
+    /// to an address the client already has configured
+    Unicast(Ipv4Address),
+    /// to the address being given to the client, at the client's hardware address
+    Client {
+        address: Ipv4Address,
+        hardware_address: EthernetAddress,
+    },
+    Broadcast,
+}
+
+impl ReplyDestination {
+    /// The endpoint the reply is sent to. The network stack finds hardware addresses with arp,
+    /// which a client can't answer before it has its address, so a reply to a client's hardware
+    /// address is broadcast instead, as RFC 2131 allows.
+    pub fn endpoint(self, server_port: u16, client_port: u16) -> IpEndpoint {
+        match self {
+            Self::RelayAgent(address) => IpEndpoint::new(address.into(), server_port),
+            Self::Unicast(address) => IpEndpoint::new(address.into(), client_port),
+            Self::Client { .. } | Self::Broadcast => {
+                IpEndpoint::new(Ipv4Address::BROADCAST.into(), client_port)
+            }
+        }
+    }
+}
+
+/// Pick where to send a reply, following RFC 2131 section 4.1:
+/// replies to relayed messages go back to the relay agent, naks are broadcast, clients that
+/// already have an address get a unicast to it, and otherwise the reply is broadcast if the
+/// client set the broadcast flag, or unicast to the address it is being given if not.
+pub fn reply_destination(reply: &DhcpRepr<'_>) -> ReplyDestination {
+    if !reply.relay_agent_ip.is_unspecified() {
+        ReplyDestination::RelayAgent(reply.relay_agent_ip)
+    } else if reply.message_type == DhcpMessageType::Nak {
+        ReplyDestination::Broadcast
+    } else if !reply.client_ip.is_unspecified() {
+        ReplyDestination::Unicast(reply.client_ip)
+    } else if reply.broadcast || reply.your_ip.is_unspecified() {
+        ReplyDestination::Broadcast
+    } else {
+        ReplyDestination::Client {
+            address: reply.your_ip,
+            hardware_address: reply.client_hardware_address,
+        }
+    }
+}
+
+/// the lease time and the renewal and rebinding times given to a client, in seconds
+#[derive(Debug, Clone, Copy)]
+pub struct LeaseTimes {
+    lease: u32,
+    renew: u32,
+    rebind: u32,
+}
+
+#[derive(Debug)]
+enum DhcpAssignment {
+    Offered {
+        transaction_id: u32,
+        identifier: ClientId,
+        offer_end_time: Instant,
+    },
+    Assigned {
+        transaction_id: u32,
+        identifier: ClientId,
+        lease_end_time: Instant,
+        hostname: Option<Hostname>,
+    },
+    /// a client reported that this address is already in use, so it can't be
+    /// handed out again until the hold-off time has passed
+    Declined {
+        hold_off_end_time: Instant,
+    },
+    /// something answered when the address was probed before offering it, so it is kept
+    /// out of the pool until the hold-off time has passed
+    Conflicted {
+        hold_off_end_time: Instant,
+    },
+    Free,
+}
+
+fn construct_packet_repr(
+    message_type: DhcpMessageType,
+    server_ip: Ipv4Address,
+    message: &ClientMessage,
+    client_ip: Ipv4Address,
+    assigned_address: Ipv4Address,
+    lease_times: Option<LeaseTimes>,
+) -> DhcpRepr<'static> {
+    DhcpRepr {
+        message_type,
+        transaction_id: message.transaction_id,
+        secs: 0,
+        client_hardware_address: message.client_hardware_address,
+        client_ip,
+        your_ip: assigned_address,
+        server_ip,
+        // the router, subnet mask and dns servers are configured options, so they're added
+        // in `emit_reply` along with the rest of the options the client asked for
+        router: None,
+        subnet_mask: None,
+        // the relay agent address and broadcast flag are copied from the client's message,
+        // so the reply finds its way back the same way
+        relay_agent_ip: message.relay_agent_ip,
+        broadcast: message.broadcast,
+        requested_ip: None,
+        client_identifier: None,
+        server_identifier: Some(server_ip),
+        parameter_request_list: None,
+        dns_servers: None,
+        max_size: None,
+        lease_duration: lease_times.map(|t| t.lease),
+        // smoltcp doesn't emit these, so `emit_reply` adds them as options
+        renew_duration: lease_times.map(|t| t.renew),
+        rebind_duration: lease_times.map(|t| t.rebind),
+        additional_options: &[],
+    }
+}
+
+pub fn construct_offer(
+    server_ip: Ipv4Address,
+    message: &ClientMessage,
+    assigned_address: Ipv4Address,
+    lease_times: LeaseTimes,
+) -> DhcpRepr<'static> {
+    construct_packet_repr(
+        DhcpMessageType::Offer,
+        server_ip,
+        message,
+        Ipv4Address::new(0, 0, 0, 0),
+        assigned_address,
+        Some(lease_times),
+    )
+}
+
+pub fn construct_ack(
+    server_ip: Ipv4Address,
+    message: &ClientMessage,
+    address: Ipv4Address,
+    lease_times: LeaseTimes,
+) -> DhcpRepr<'static> {
+    construct_packet_repr(
+        DhcpMessageType::Ack,
+        server_ip,
+        message,
+        message.client_ip,
+        address,
+        Some(lease_times),
+    )
+}
+
+/// an ack in reply to an inform only carries configuration parameters:
+/// the client already has an address, so there's no `your_ip` or lease time
+pub fn construct_inform_ack(server_ip: Ipv4Address, message: &ClientMessage) -> DhcpRepr<'static> {
+    construct_packet_repr(
+        DhcpMessageType::Ack,
+        server_ip,
+        message,
+        message.client_ip,
+        Ipv4Address::new(0, 0, 0, 0),
+        None,
+    )
+}
+
+/// a nak carries the server identifier and leaves `ciaddr`, `yiaddr` and `siaddr` empty
+/// (RFC 2131 table 3)
+pub fn construct_nack(server_ip: Ipv4Address, message: &ClientMessage) -> DhcpRepr<'static> {
+    DhcpRepr {
+        // the client might not have the address it asked about, so a relay agent has to
+        // broadcast the nak to it (RFC 2131 section 4.1)
+        broadcast: message.broadcast || !message.relay_agent_ip.is_unspecified(),
+        server_ip: Ipv4Address::new(0, 0, 0, 0),
+        ..construct_packet_repr(
+            DhcpMessageType::Nak,
+            server_ip,
+            message,
+            Ipv4Address::new(0, 0, 0, 0),
+            Ipv4Address::new(0, 0, 0, 0),
+            None,
+        )
+    }
+}
+
+/// The addresses the server hands out and what has happened to each of them, along with the
+/// configuration that decides which client gets which. None of this touches the network or the
+/// flash, which the `DhcpServer` around it looks after.
+pub struct Leases<const N_ADDRESSES: usize> {
+    server_address: Ipv4Address,
+    pools: Vec<Pool, MAX_POOLS>,
+    classes: Vec<Class, MAX_CLASSES>,
+    assignments: [DhcpAssignment; N_ADDRESSES],
+    reservations: Vec<Reservation, MAX_RESERVATIONS>,
+    /// the slot each client was last given a lease in, with the most recent last
+    last_slots: Vec<(ClientId, usize), MAX_REMEMBERED_CLIENTS>,
+    access: AccessControl,
+    offer_time: Duration,
+    renew_ratio: f32,
+    rebind_ratio: f32,
+    decline_hold_off: Duration,
+    /// whether the leases have changed since they were last written to flash
+    leases_changed: bool,
+}
+
+impl<const N_ADDRESSES: usize> Leases<N_ADDRESSES> {
+    /// Set up the pools, classes and reservations from the configuration.
+    /// Returns None if the configuration can't be used.
+    pub fn new(config: DhcpConfig<'_>) -> Option<Self> {
+        let DhcpConfig {
+            server_address,
+            pools: pool_configs,
+            classes: class_configs,
+            offer_time,
+            renew_ratio,
+            rebind_ratio,
+            decline_hold_off,
+            reservations,
+            access,
+            captive_portal_api,
+            ..
+        } = config;
+        if captive_portal_api.is_some_and(|uri| !uri.starts_with("https://")) {
+            return None;
+        }
+        let mut classes: Vec<Class, MAX_CLASSES> = Vec::new();
+        for (i, class_config) in class_configs.iter().enumerate() {
+            if class_configs[..i]
+                .iter()
+                .any(|class| class.name == class_config.name)
+            {
+                return None;
+            }
+            classes.push(Class::new(class_config)?).ok()?;
+        }
+        // the pools are given runs of assignment slots in order, and there have to be enough
+        // slots for all of their addresses
+        let mut pools: Vec<Pool, MAX_POOLS> = Vec::new();
+        let mut first_slot = 0;
+        for pool_config in pool_configs {
+            let mut pool = Pool::new(pool_config, first_slot, class_configs)?;
+            if let Some(uri) = captive_portal_api {
+                pool.options
+                    .set(DHCP_OPT_CAPTIVE_PORTAL, uri.as_bytes())
+                    .ok()?;
+            }
+            first_slot = pool.slots().end;
+            pools.push(pool).ok()?;
+        }
+        if pools.is_empty() || first_slot > N_ADDRESSES {
+            return None;
+        }
+        // the server can't hand out its own address, and no two pools can share addresses
+        for (i, pool) in pools.iter().enumerate() {
+            let first_address = pool.addresses.address(0)?;
+            if pool.addresses.index(server_address).is_some()
+                || Self::pool_slot(&pools[..i], first_address).is_some()
+                || pools[..i].iter().any(|other| {
+                    other
+                        .addresses
+                        .address(0)
+                        .is_some_and(|a| pool.addresses.index(a).is_some())
+                })
+            {
+                return None;
+            }
+        }
+        // clients have to renew before they rebind, and both before the lease runs out
+        if !(0.0 < renew_ratio && renew_ratio < rebind_ratio && rebind_ratio < 1.0) {
+            return None;
+        }
+        // every reservation has to be for an address in the pool, and no address can be
+        // reserved twice
+        for (i, reservation) in reservations.iter().enumerate() {
+            Self::pool_slot(&pools, reservation.address)?;
+            if Self::is_reserved(&reservations[..i], reservation.address) {
+                return None;
+            }
+        }
+        Some(Self {
+            server_address,
+            pools,
+            classes,
+            assignments: [(); N_ADDRESSES].map(|_| DhcpAssignment::Free),
+            reservations: Vec::from_slice(reservations).ok()?,
+            last_slots: Vec::new(),
+            access: AccessControl::new(&access)?,
+            offer_time,
+            renew_ratio,
+            rebind_ratio,
+            decline_hold_off,
+            leases_changed: false,
+        })
+    }
+
+    /// Put back a lease that was stored in flash before the last reboot. The clock started
+    /// again from zero, so the lease ends its remaining time from now.
+    /// The pools and reservations might have changed since the lease was stored, so it is
+    /// dropped if its slot isn't in a pool any more, or its address is reserved for another
+    /// client.
+    pub fn restore_lease(&mut self, lease: StoredLease, now: Instant) {
+        let Some(identifier) = ClientId::new(&lease.identifier) else {
+            return;
+        };
+        let index = lease.index as usize;
+        let usable = index < N_ADDRESSES
+            && self.slot_address(index).is_some_and(|address| {
+                self.reservations
+                    .iter()
+                    .filter(|r| r.address == address)
+                    .all(|r| r.is_for(&identifier))
+            });
+        if !usable {
+            log::warn!(
+                "Dropped the stored lease on slot {}, which isn't free to use",
+                lease.index
+            );
+            return;
+        }
+        log::info!("Restored lease on slot {}", lease.index);
+        self.assignments[index] = DhcpAssignment::Assigned {
+            transaction_id: 0,
+            identifier: identifier.clone(),
+            lease_end_time: now + Duration::from_secs(lease.remaining_secs as u64),
+            hostname: None,
+        };
+        self.remember_slot(&identifier, index);
+    }
+
+    /// the leases that haven't run out, as they are written to flash
+    pub fn stored_leases(&self, now: Instant) -> impl Iterator<Item = StoredLease> + '_ {
+        self.assignments.iter().enumerate().filter_map(
+            move |(index, assignment)| match assignment {
+                DhcpAssignment::Assigned {
+                    identifier,
+                    lease_end_time,
+                    ..
+                } if now < *lease_end_time => Some(StoredLease {
+                    index: index as u16,
+                    remaining_secs: (*lease_end_time - now).as_secs() as u32,
+                    identifier: Vec::from_slice(identifier.as_bytes()).ok()?,
+                }),
+                _ => None,
+            },
+        )
+    }
+
+    /// Remember the slot a client was given a lease in, forgetting the client that was given
+    /// one longest ago if there's no room.
+    fn remember_slot(&mut self, id: &ClientId, index: usize) {
+        if let Some(position) = self.last_slots.iter().position(|(i, _)| i == id) {
+            self.last_slots.remove(position);
+        } else if self.last_slots.is_full() {
+            self.last_slots.remove(0);
+        }
+        let _ = self.last_slots.push((id.clone(), index));
+    }
+
+    /// the lease time for clients of a pool, which their class can override
+    fn lease_time(&self, pool: usize, class: Option<usize>) -> Duration {
+        class
+            .and_then(|class| self.classes[class].lease_time)
+            .unwrap_or(self.pools[pool].lease_time)
+    }
+
+    /// the lease time given to a client, along with the times it should renew and rebind
+    pub fn lease_times(&self, pool: usize, class: Option<usize>) -> LeaseTimes {
+        let lease = self.lease_time(pool, class).as_secs().min(u32::MAX as u64) as u32;
+        LeaseTimes {
+            lease,
+            renew: (lease as f32 * self.renew_ratio) as u32,
+            rebind: (lease as f32 * self.rebind_ratio) as u32,
+        }
+    }
+
+    /// Emit a reply to `message` into `buffer`, returning its length and where it should go.
+    /// The options go in this order: the renewal and rebinding times, the `reply_to` options
+    /// that always go in the reply, then if the reply carries configuration from a pool, the
+    /// options the client asked for from its class and then from the pool. Options that would
+    /// make the reply bigger than the client can accept are left out. Relay agent information
+    /// from the relay is echoed back as the very last option, with room kept for it up front.
+    /// Pools set up for network booting also fill in `siaddr` and the `file` field.
+    pub fn emit_reply(
+        &self,
+        packet_repr: DhcpRepr<'_>,
+        message: &ClientMessage,
+        reply_to: &[DhcpOption<'_>],
+        pool: Option<usize>,
+        buffer: &mut [u8],
+    ) -> Result<(usize, ReplyDestination)> {
+        let requested_options = &message.requested_options;
+        let max_len =
+            (requested_options.max_message_size as usize - IP_UDP_HEADER_LEN).min(buffer.len());
+        let mut reply_options = ReplyOptions::new(max_len.saturating_sub(packet_repr.buffer_len()));
+        // the relay agent information goes back as the last option (RFC 3046 section 2.1), so
+        // room is kept for it before anything else goes in
+        let relay_agent_information = message
+            .relay_agent_information
+            .as_deref()
+            .filter(|information| reply_options.reserve_last(information.len()));
+        if let Some(renew) = packet_repr.renew_duration {
+            reply_options.push(DHCP_OPT_RENEWAL_TIME, &renew.to_be_bytes());
+        }
+        if let Some(rebind) = packet_repr.rebind_duration {
+            reply_options.push(DHCP_OPT_REBINDING_TIME, &rebind.to_be_bytes());
+        }
+        for option in reply_to {
+            reply_options.push(option.kind, option.data);
+        }
+        if let Some(pool) = pool.and_then(|pool| self.pools.get(pool)) {
+            // options the class has replace the pool's, since only the first of each is kept
+            if let Some(class) = message.class.and_then(|class| self.classes.get(class)) {
+                class.options.select(
+                    requested_options.parameter_request_list.as_deref(),
+                    &mut reply_options,
+                );
+            }
+            pool.options.select(
+                requested_options.parameter_request_list.as_deref(),
+                &mut reply_options,
+            );
+        }
+        if let Some(information) = relay_agent_information {
+            reply_options.push_last(DHCP_OPT_RELAY_AGENT_INFORMATION, information);
+        }
+        let options = reply_options.options();
+        let pool = pool.and_then(|pool| self.pools.get(pool));
+        let packet_repr = DhcpRepr {
+            server_ip: pool
+                .and_then(|pool| pool.boot_server)
+                .unwrap_or(packet_repr.server_ip),
+            additional_options: &options,
+            ..packet_repr
+        };
+        let len = packet_repr.buffer_len();
+
+        let mut packet = DhcpPacket::new_checked(&mut *buffer)?;
+        packet_repr.emit(&mut packet)?;
+        if let Some(pool) = pool {
+            buffer[BOOT_FILE_FIELD][..pool.boot_file.len()].copy_from_slice(&pool.boot_file);
+        }
+        Ok((len, reply_destination(&packet_repr)))
+    }
+
+    /// The pool to give the client an address from. Messages that came through a relay agent
+    /// are for the subnet the relay agent is on. A client that already has an address renews
+    /// by sending straight to the server, even from behind a relay agent, so its messages are
+    /// for the subnet of that address if there's a pool on it. Anything else is for the subnet
+    /// of the interface the server listens on. The first pool on the subnet which serves the
+    /// client's class is used.
+    pub fn select_pool(&self, message: &ClientMessage) -> Option<usize> {
+        let on_subnet = |address: Ipv4Address| {
+            self.pools.iter().position(|pool| {
+                pool.addresses.contains(address) && pool.serves_class(message.class)
+            })
+        };
+        if !message.relay_agent_ip.is_unspecified() {
+            on_subnet(message.relay_agent_ip)
+        } else if !message.client_ip.is_unspecified() {
+            on_subnet(message.client_ip).or_else(|| on_subnet(self.server_address))
+        } else {
+            on_subnet(self.server_address)
+        }
+    }
+
+    /// the first client class the client matches
+    pub fn classify(&self, message: &ClientMessage) -> Option<usize> {
+        self.classes.iter().position(|class| {
+            class.matcher.matches(
+                message.vendor_class.as_deref(),
+                message.user_class.as_deref(),
+                message.client_hardware_address,
+            )
+        })
+    }
+
+    /// the assignment slot an address uses in one of these pools, if there is one
+    fn pool_slot(pools: &[Pool], address: Ipv4Address) -> Option<usize> {
+        pools.iter().find_map(|pool| {
+            pool.addresses
+                .index(address)
+                .map(|index| pool.first_slot + index)
+        })
+    }
+
+    /// the address of the server on the interface it listens on
+    pub fn server_address(&self) -> Ipv4Address {
+        self.server_address
+    }
+
+    /// the assignment slots of a pool's addresses
+    pub fn pool_slots(&self, pool: usize) -> Range<usize> {
+        self.pools[pool].slots()
+    }
+
+    /// whether clients that aren't allowed an address are ignored rather than sent a nak
+    pub fn ignores_unknown_clients(&self) -> bool {
+        self.access.ignore_unknown_clients
+    }
+
+    /// whether the leases have changed since they were last written to flash
+    pub fn leases_changed(&self) -> bool {
+        self.leases_changed
+    }
+
+    /// Note that the leases have just been written to flash.
+    pub fn leases_saved(&mut self) {
+        self.leases_changed = false;
+    }
+
+    /// find the index of the assignment slot that an address belongs to, if there is one
+    fn address_index(&self, address: Ipv4Address) -> Option<usize> {
+        Self::pool_slot(&self.pools, address).filter(|&index| index < N_ADDRESSES)
+    }
+
+    /// the address that uses an assignment slot
+    pub fn slot_address(&self, slot: usize) -> Option<Ipv4Address> {
+        self.pools
+            .iter()
+            .find(|pool| pool.slots().contains(&slot))
+            .and_then(|pool| pool.addresses.address(slot - pool.first_slot))
+    }
+
+    /// the address in this pool that uses an assignment slot
+    fn pool_address(pool: &Pool, slot: usize) -> Option<Ipv4Address> {
+        slot.checked_sub(pool.first_slot)
+            .and_then(|index| pool.addresses.address(index))
+    }
+
+    /// whether the address is reserved for any client
+    fn is_reserved(reservations: &[Reservation], address: Ipv4Address) -> bool {
+        reservations.iter().any(|r| r.address == address)
+    }
+
+    /// the index of the assignment slot reserved for this client, if it has one
+    pub fn reserved_index(
+        &self,
+        client_hardware_address: EthernetAddress,
+        client_identifier: Option<&ClientId>,
+    ) -> Option<usize> {
+        self.reservations
+            .iter()
+            .find(|r| r.matches(client_hardware_address, client_identifier))
+            .and_then(|r| self.address_index(r.address))
+    }
+
+    /// whether the allow and deny lists let the client have an address
+    pub fn is_allowed(&self, message: &ClientMessage) -> bool {
+        let has_reservation = self
+            .reserved_index(
+                message.client_hardware_address,
+                message.client_identifier.as_ref(),
+            )
+            .is_some();
+        self.access
+            .is_allowed(message.client_hardware_address, has_reservation)
+    }
+
+    /// whether the address in a slot was declined and is still held off
+    pub fn is_declined(&self, index: usize, now: Instant) -> bool {
+        matches!(self.assignments[index],
+            DhcpAssignment::Declined { hold_off_end_time } if now < hold_off_end_time)
+    }
+
+    /// the hostname of the client that has a lease on a slot
+    pub fn hostname(&self, index: usize) -> Option<&Hostname> {
+        match &self.assignments[index] {
+            DhcpAssignment::Assigned { hostname, .. } => hostname.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// whether a slot is offered or leased to this client
+    pub fn is_clients(&self, index: usize, id: &ClientId) -> bool {
+        matches!(&self.assignments[index],
+            DhcpAssignment::Offered { identifier, .. }
+            | DhcpAssignment::Assigned { identifier, .. } if identifier == id)
+    }
+
+    /// Offer the address in a slot to the client that sent `message`.
+    pub fn offer(&mut self, index: usize, message: &ClientMessage, now: Instant) -> Result<()> {
+        let offer_end_time = now.checked_add(self.offer_time).ok_or(Error)?;
+        self.expire(index, now);
+        self.assignments[index] = DhcpAssignment::Offered {
+            transaction_id: message.transaction_id,
+            identifier: message.identifier(),
+            offer_end_time,
+        };
+        Ok(())
+    }
+
+    /// Keep the address in a slot that something answered a probe on out of the pool until the
+    /// hold-off time has passed.
+    pub fn mark_conflicted(&mut self, index: usize, now: Instant) -> Result<()> {
+        let hold_off_end_time = now.checked_add(self.decline_hold_off).ok_or(Error)?;
+        self.expire(index, now);
+        self.assignments[index] = DhcpAssignment::Conflicted { hold_off_end_time };
+        Ok(())
+    }
+
+    /// The slot to offer a client from the pool's slots, leaving out reserved ones.
+    /// A client that already has an offer or a lease keeps it, and a client that had a lease
+    /// before gets the same slot back if it is free. Otherwise the slots are searched starting
+    /// from one picked by hashing the client's identifier, so a client usually gets the same
+    /// address each time. The first slot which isn't offered, leased or held off is used, or if
+    /// there isn't one, the first one that has been offered to a different client.
+    pub fn offer_candidate(
+        &self,
+        id: &ClientId,
+        slots: &Range<usize>,
+        pool: usize,
+        now: Instant,
+    ) -> Option<usize> {
+        let unreserved = |index: &usize| {
+            !Self::pool_address(&self.pools[pool], *index)
+                .is_some_and(|a| Self::is_reserved(&self.reservations, a))
+        };
+        let is_free = |index: &usize| match &self.assignments[*index] {
+            // an offer that has expired is free to use
+            DhcpAssignment::Offered { offer_end_time, .. } => *offer_end_time <= now,
+            DhcpAssignment::Assigned { lease_end_time, .. } => *lease_end_time <= now,
+            // the address was declined or something else answered on it, and it is held off
+            // until then
+            DhcpAssignment::Declined { hold_off_end_time }
+            | DhcpAssignment::Conflicted { hold_off_end_time } => *hold_off_end_time <= now,
+            DhcpAssignment::Free => true,
+        };
+        // if the address has been offered to another client, it can be taken if there's
+        // nothing else
+        let is_offered =
+            |index: &usize| matches!(&self.assignments[*index], DhcpAssignment::Offered { .. });
+
+        if let Some(index) = slots
+            .clone()
+            .filter(unreserved)
+            .find(|index| self.is_clients(*index, id))
+        {
+            return Some(index);
+        }
+        let last_slot = self
+            .last_slots
+            .iter()
+            .find(|(identifier, _)| identifier == id)
+            .map(|&(_, index)| index);
+        if let Some(index) =
+            last_slot.filter(|index| slots.contains(index) && unreserved(index) && is_free(index))
+        {
+            return Some(index);
+        }
+        let size = slots.len();
+        if size == 0 {
+            return None;
+        }
+        let preferred = id.stable_hash() as usize % size;
+        let search = (0..size)
+            .map(|i| slots.start + (preferred + i) % size)
+            .filter(unreserved);
+        search
+            .clone()
+            .find(is_free)
+            .or_else(|| search.clone().find(is_offered))
+    }
+
+    /// Given a request message from a client, give it the address it was offered or renew the
+    /// lease it has. Returns the slot to send an ack for, or None if the client should be sent
+    /// a nak.
+    pub fn process_request(
+        &mut self,
+        message: &ClientMessage,
+        pool: usize,
+        now: Instant,
+    ) -> Result<Option<usize>> {
+        let id = message.identifier();
+        let previous_lease = self.lease_index(&id, now);
+        let transaction_id = message.transaction_id;
+        let new_lease_time = now
+            .checked_add(self.lease_time(pool, message.class))
+            .ok_or(smoltcp::wire::Error)?;
+        let slots = self.pools[pool].slots();
+        // a client with a reservation can only ever be given its reserved address
+        if let Some(index) = self
+            .reserved_index(
+                message.client_hardware_address,
+                message.client_identifier.as_ref(),
+            )
+            .filter(|index| slots.contains(index))
+        {
+            let address = message.requested_ip.unwrap_or(message.client_ip);
+            let requested_index = self.address_index(address);
+            if requested_index != Some(index) || self.is_declined(index, now) {
+                return Ok(None);
+            }
+            self.publish_expiry(index, now);
+            let assignment = &mut self.assignments[index];
+            let hostname = match assignment {
+                DhcpAssignment::Assigned { hostname, .. } => hostname.take(),
+                _ => None,
+            };
+            *assignment = DhcpAssignment::Assigned {
+                transaction_id,
+                identifier: id.clone(),
+                lease_end_time: new_lease_time,
+                hostname,
+            };
+            self.leases_changed = true;
+            self.update_hostname(index, message, now);
+            self.report_lease(index, previous_lease == Some(index));
+            self.remember_slot(&id, index);
+            return Ok(Some(index));
+        }
+        // a client that is selecting an offer or rebooting puts the address it wants in the
+        // requested ip option, and a client that is renewing or rebinding has it in `client_ip`
+        let address = message.requested_ip.unwrap_or(message.client_ip);
+        let mut requested_index = None;
+        if !address.is_unspecified() {
+            if Self::is_reserved(&self.reservations, address) {
+                return Ok(None);
+            }
+            // an address that isn't in any pool is wrong for the client's network
+            // (RFC 2131 4.3.2), so it is naked rather than ignored
+            let Some(address_index) = self.address_index(address) else {
+                log::warn!("Address {} isn't in any pool", address);
+                return Ok(None);
+            };
+            if !slots.contains(&address_index) {
+                log::warn!("Address {} is from the wrong pool", address);
+                return Ok(None);
+            }
+            requested_index = Some(address_index);
+            // the client's offer or lease is taken up again even if it has run out, but its
+            // expiry is still published
+            if self.is_clients(address_index, &id) {
+                self.publish_expiry(address_index, now);
+            }
+            let assignment = &mut self.assignments[address_index];
+
+            match assignment {
+                DhcpAssignment::Offered { identifier, .. } if *identifier == id => {
+                    *assignment = DhcpAssignment::Assigned {
+                        transaction_id,
+                        identifier: id.clone(),
+                        lease_end_time: new_lease_time,
+                        hostname: None,
+                    };
+                }
+                DhcpAssignment::Assigned {
+                    transaction_id: tid,
+                    identifier,
+                    lease_end_time,
+                    ..
+                } if *identifier == id => {
+                    *tid = transaction_id;
+                    *lease_end_time = new_lease_time;
+                }
+                _ => {}
+            };
+        }
+        let mut assigned_index = None;
+        for (i, assignment) in self.assignments.iter_mut().enumerate() {
+            if !slots.contains(&i)
+                || Self::pool_address(&self.pools[pool], i)
+                    .is_some_and(|a| Self::is_reserved(&self.reservations, a))
+            {
+                continue;
+            }
+            match assignment {
+                // we have offered an assignment with this transaction_id to this identifier
+                DhcpAssignment::Offered {
+                    identifier: a_identifier,
+                    transaction_id: a_transaction_id,
+                    ..
+                } if (*a_identifier == id) && (*a_transaction_id == transaction_id) => {
+                    if let Some(event) = Self::pool_address(&self.pools[pool], i)
+                        .and_then(|address| Self::expiry(assignment, address, now))
+                    {
+                        publish_event(event);
+                    }
+                    *assignment = DhcpAssignment::Assigned {
+                        identifier: id.clone(),
+                        lease_end_time: new_lease_time,
+                        transaction_id,
+                        hostname: None,
+                    };
+                    assigned_index = Some(i);
+                    break;
+                }
+                // we have already assigned an ip to this identifier, the lease time isn't up, and
+                // it's the address the client asked for if it asked for one. a client that is
+                // renewing uses a new transaction_id, so that doesn't have to match
+                DhcpAssignment::Assigned {
+                    identifier: a_identifier,
+                    transaction_id: a_transaction_id,
+                    lease_end_time,
+                    ..
+                } if (*a_identifier == id)
+                    && (now < *lease_end_time)
+                    && requested_index.unwrap_or(i) == i =>
+                {
+                    *a_transaction_id = transaction_id;
+                    *lease_end_time = new_lease_time;
+                    assigned_index = Some(i);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let Some(i) = assigned_index else {
+            return Ok(None);
+        };
+        self.leases_changed = true;
+        self.update_hostname(i, message, now);
+        self.report_lease(i, previous_lease == Some(i));
+        self.remember_slot(&id, i);
+        Ok(Some(i))
+    }
+
+    /// the slot of the lease a client has, if it has one that hasn't run out
+    fn lease_index(&self, id: &ClientId, now: Instant) -> Option<usize> {
+        self.assignments.iter().position(|assignment| {
+            matches!(assignment, DhcpAssignment::Assigned { identifier, lease_end_time, .. }
+                if identifier == id && now < *lease_end_time)
+        })
+    }
+
+    /// the lease in a slot, as it is reported in events
+    fn lease_info(&self, index: usize) -> Option<LeaseInfo> {
+        match &self.assignments[index] {
+            DhcpAssignment::Assigned {
+                identifier,
+                lease_end_time,
+                hostname,
+                ..
+            } => Some(LeaseInfo {
+                address: self.slot_address(index)?,
+                identifier: identifier.clone(),
+                hostname: hostname.clone(),
+                lease_end: *lease_end_time,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Publish an event for the lease that was just given out in a slot.
+    fn report_lease(&self, index: usize, renewed: bool) {
+        if let Some(lease) = self.lease_info(index) {
+            publish_event(if renewed {
+                DhcpEvent::LeaseRenewed(lease)
+            } else {
+                DhcpEvent::LeaseAssigned(lease)
+            });
+        }
+    }
+
+    /// Give the lease at `index` the hostname its client sent, made unique among the other
+    /// leases. A client that didn't send a hostname keeps the one it had, and a client that
+    /// asked for no dns updates in its client fqdn option has its hostname removed.
+    fn update_hostname(&mut self, index: usize, message: &ClientMessage, now: Instant) {
+        if message
+            .client_fqdn
+            .as_ref()
+            .is_some_and(|fqdn| !fqdn.allows_updates())
+        {
+            if let DhcpAssignment::Assigned { hostname, .. } = &mut self.assignments[index] {
+                *hostname = None;
+            }
+            return;
+        }
+        let Some(hostname) = message.hostname.as_ref() else {
+            return;
+        };
+        let assignments = &self.assignments;
+        let unique = deduplicate(hostname.clone(), |name| {
+            assignments.iter().enumerate().any(|(i, assignment)| {
+                i != index
+                    && matches!(assignment, DhcpAssignment::Assigned {
+                        hostname: Some(other),
+                        lease_end_time,
+                        ..
+                    } if now < *lease_end_time && other.as_str() == name)
+            })
+        });
+        if let DhcpAssignment::Assigned { hostname, .. } = &mut self.assignments[index] {
+            *hostname = unique;
+        }
+    }
+
+    /// Publish the leases that haven't run out to the shared lease table, where the dns server
+    /// and the captive portal api read them from.
+    pub fn publish_leases(&self, now: Instant) {
+        lease_table::publish(
+            (0..N_ADDRESSES)
+                .filter_map(|index| self.lease_info(index))
+                .filter(|lease| now < lease.lease_end),
+        );
+    }
+
+    /// Given a release message from a client, free the address it was leased.
+    /// The release is only honoured if it is addressed to this server, and the
+    /// address in `client_ip` is currently assigned to the releasing identifier.
+    pub fn process_release(&mut self, message: &ClientMessage, now: Instant) -> Result<()> {
+        if message.server_identifier != Some(self.server_address) {
+            return Err(Error);
+        }
+        let id = message.identifier();
+        let address_index = self.address_index(message.client_ip).ok_or(Error)?;
+        let mut lease = self.lease_info(address_index).ok_or(Error)?;
+        if lease.identifier != id {
+            return Err(Error);
+        }
+        log::info!("Released lease on {}", message.client_ip);
+        self.assignments[address_index] = DhcpAssignment::Free;
+        self.leases_changed = true;
+        lease.lease_end = now;
+        publish_event(DhcpEvent::LeaseReleased(lease));
+        Ok(())
+    }
+
+    /// Given a decline message from a client, the address we gave it is already in use
+    /// by something else on the network. Take the address out of circulation
+    /// for `decline_hold_off`, so that it isn't offered to anyone until then.
+    /// If the client had a lease on the address, the lease ends now.
+    pub fn process_decline(&mut self, message: &ClientMessage, now: Instant) -> Result<()> {
+        if message.server_identifier != Some(self.server_address) {
+            return Err(Error);
+        }
+        let id = message.identifier();
+        let address = message.requested_ip.ok_or(Error)?;
+        let address_index = self.address_index(address).ok_or(Error)?;
+        let hold_off_end_time = now.checked_add(self.decline_hold_off).ok_or(Error)?;
+        match &self.assignments[address_index] {
+            DhcpAssignment::Offered { identifier, .. }
+            | DhcpAssignment::Assigned { identifier, .. }
+                if *identifier == id =>
+            {
+                log::warn!("Address {} was declined, holding it off", address);
+                if let Some(mut lease) = self.lease_info(address_index) {
+                    lease.lease_end = now;
+                    publish_event(DhcpEvent::LeaseDeclined(lease));
+                }
+                self.assignments[address_index] = DhcpAssignment::Declined { hold_off_end_time };
+                self.leases_changed = true;
+                Ok(())
+            }
+            _ => Err(Error),
+        }
+    }
+
+    /// the event published when the offer or lease in a slot with this address has run out,
+    /// or None if it hasn't
+    fn expiry(
+        assignment: &DhcpAssignment,
+        address: Ipv4Address,
+        now: Instant,
+    ) -> Option<DhcpEvent> {
+        match assignment {
+            DhcpAssignment::Offered {
+                identifier,
+                offer_end_time,
+                ..
+            } if *offer_end_time <= now => Some(DhcpEvent::OfferExpired {
+                address,
+                identifier: identifier.clone(),
+            }),
+            DhcpAssignment::Assigned {
+                identifier,
+                lease_end_time,
+                hostname,
+                ..
+            } if *lease_end_time <= now => Some(DhcpEvent::LeaseExpired(LeaseInfo {
+                address,
+                identifier: identifier.clone(),
+                hostname: hostname.clone(),
+                lease_end: *lease_end_time,
+            })),
+            _ => None,
+        }
+    }
+
+    /// Publish the expiry of the offer or lease in a slot if it has run out, returning whether
+    /// it had. This is done before a slot is overwritten, so that the expiry isn't lost when
+    /// the slot is used again before the sweep gets to it.
+    fn publish_expiry(&self, index: usize, now: Instant) -> bool {
+        let event = self
+            .slot_address(index)
+            .and_then(|address| Self::expiry(&self.assignments[index], address, now));
+        match event {
+            Some(event) => {
+                publish_event(event);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Free a slot if its offer or lease has run out, publishing the expiry.
+    fn expire(&mut self, index: usize, now: Instant) {
+        if !self.publish_expiry(index, now) {
+            return;
+        }
+        if matches!(self.assignments[index], DhcpAssignment::Assigned { .. }) {
+            self.leases_changed = true;
+        }
+        self.assignments[index] = DhcpAssignment::Free;
+        if let Some(address) = self.slot_address(index) {
+            log::info!("Reclaimed {}", address);
+        }
+    }
+
+    /// Free every offer and lease that has run out, and every declined address whose hold-off
+    /// has passed. Expired offers and leases are published on `DHCP_EVENTS`.
+    pub fn sweep_assignments(&mut self, now: Instant) {
+        for index in 0..N_ADDRESSES {
+            self.expire(index, now);
+            if let DhcpAssignment::Declined { hold_off_end_time }
+            | DhcpAssignment::Conflicted { hold_off_end_time } = self.assignments[index]
+            {
+                if hold_off_end_time <= now {
+                    self.assignments[index] = DhcpAssignment::Free;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_id::MAX_CLIENT_ID_LEN;
+
+    const SERVER_ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 1, 1);
+    const HARDWARE_ADDRESS: [u8; 6] = [0x28, 0xcd, 0xc1, 0x00, 0x00, 0x01];
+
+    fn leases() -> Leases<10> {
+        leases_with(&[])
+    }
+
+    fn leases_with<const N: usize>(reservations: &[Reservation]) -> Leases<N> {
+        let pools = [pool_config()];
+        Leases::new(config(&pools, reservations)).unwrap()
+    }
+
+    fn pool_config() -> PoolConfig<'static> {
+        PoolConfig {
+            pool: AddressPool::new(
+                Ipv4Address::new(192, 168, 1, 0),
+                24,
+                Ipv4Address::new(192, 168, 1, 2),
+                Ipv4Address::new(192, 168, 1, 11),
+            )
+            .unwrap(),
+            lease_time: Duration::from_secs(60 * 60),
+            routers: &[SERVER_ADDRESS],
+            static_routes: &[],
+            dns_server: SERVER_ADDRESS,
+            client_class: None,
+            options: &[],
+            network_boot: None,
+        }
+    }
+
+    fn config<'c>(pools: &'c [PoolConfig<'c>], reservations: &'c [Reservation]) -> DhcpConfig<'c> {
+        DhcpConfig {
+            server_address: SERVER_ADDRESS,
+            pools,
+            classes: &[],
+            offer_time: Duration::from_secs(60),
+            renew_ratio: DEFAULT_RENEW_RATIO,
+            rebind_ratio: DEFAULT_REBIND_RATIO,
+            decline_hold_off: Duration::from_secs(10 * 60),
+            probe_timeout: None,
+            client_rate_limit: RateLimit {
+                burst: 8,
+                refill_interval: Duration::from_secs(2),
+            },
+            max_packets_per_second: 50,
+            reservations,
+            access: AccessConfig {
+                allow_list: &[],
+                deny_list: &[],
+                ignore_unknown_clients: false,
+            },
+            captive_portal_api: None,
+        }
+    }
+
+    fn message(
+        message_type: DhcpMessageType,
+        hardware_address: [u8; 6],
+        client_identifier: Option<&[u8]>,
+    ) -> ClientMessage {
+        ClientMessage {
+            message_type,
+            transaction_id: 1,
+            client_hardware_address: EthernetAddress(hardware_address),
+            client_identifier: client_identifier.and_then(ClientId::new),
+            client_ip: Ipv4Address::UNSPECIFIED,
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            relay_agent_information: None,
+            vendor_class: None,
+            user_class: None,
+            class: None,
+            broadcast: false,
+            requested_ip: None,
+            server_identifier: None,
+            client_fqdn: None,
+            hostname: None,
+            requested_options: RequestedOptions::new(None, None),
+        }
+    }
+
+    /// give the client that sent `message` a lease on a slot until `lease_end_time`
+    fn assign(
+        leases: &mut Leases<10>,
+        index: usize,
+        message: &ClientMessage,
+        lease_end_time: Instant,
+    ) {
+        leases.assignments[index] = DhcpAssignment::Assigned {
+            transaction_id: message.transaction_id,
+            identifier: message.identifier(),
+            lease_end_time,
+            hostname: None,
+        };
+    }
+
+    fn release(index: usize, leases: &Leases<10>, hardware_address: [u8; 6]) -> ClientMessage {
+        ClientMessage {
+            client_ip: leases.slot_address(index).unwrap(),
+            server_identifier: Some(SERVER_ADDRESS),
+            ..message(DhcpMessageType::Release, hardware_address, None)
+        }
+    }
+
+    #[test]
+    fn release_frees_the_clients_lease() {
+        let mut leases = leases();
+        let now = Instant::from_secs(100);
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        assign(&mut leases, 3, &client, now + Duration::from_secs(60));
+
+        let release = release(3, &leases, HARDWARE_ADDRESS);
+        assert!(leases.process_release(&release, now).is_ok());
+        assert!(matches!(leases.assignments[3], DhcpAssignment::Free));
+        assert!(leases.leases_changed);
+    }
+
+    #[test]
+    fn release_from_another_client_is_ignored() {
+        let mut leases = leases();
+        let now = Instant::from_secs(100);
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        assign(&mut leases, 3, &client, now + Duration::from_secs(60));
+
+        let release = release(3, &leases, [0x28, 0xcd, 0xc1, 0x00, 0x00, 0x02]);
+        assert!(leases.process_release(&release, now).is_err());
+        assert!(leases.is_clients(3, &client.identifier()));
+    }
+
+    #[test]
+    fn release_to_another_server_is_ignored() {
+        let mut leases = leases();
+        let now = Instant::from_secs(100);
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        assign(&mut leases, 3, &client, now + Duration::from_secs(60));
+
+        let release = ClientMessage {
+            server_identifier: Some(Ipv4Address::new(192, 168, 1, 254)),
+            ..release(3, &leases, HARDWARE_ADDRESS)
+        };
+        assert!(leases.process_release(&release, now).is_err());
+        assert!(leases.is_clients(3, &client.identifier()));
+    }
+
+    /// lease every slot but `free` to a client of its own
+    fn fill_except(leases: &mut Leases<10>, free: usize, lease_end_time: Instant) {
+        for index in (0..10).filter(|&index| index != free) {
+            let other = message(DhcpMessageType::Request, [2, 0, 0, 0, 0, index as u8], None);
+            assign(leases, index, &other, lease_end_time);
+        }
+    }
+
+    #[test]
+    fn declined_address_is_held_off() {
+        let mut leases = leases();
+        let now = Instant::from_secs(100);
+        let mut events = DHCP_EVENTS.subscriber().unwrap();
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        assign(&mut leases, 4, &client, now + Duration::from_secs(60));
+        fill_except(&mut leases, 4, now + Duration::from_secs(60 * 60));
+
+        let decline = ClientMessage {
+            requested_ip: leases.slot_address(4),
+            server_identifier: Some(SERVER_ADDRESS),
+            ..message(DhcpMessageType::Decline, HARDWARE_ADDRESS, None)
+        };
+        assert!(leases.process_decline(&decline, now).is_ok());
+        assert!(matches!(
+            leases.assignments[4],
+            DhcpAssignment::Declined { .. }
+        ));
+        // other tests publish events at the same time, so only this one is looked for
+        let address = leases.slot_address(4).unwrap();
+        let declined = core::iter::from_fn(|| events.try_next_message_pure()).any(|event| {
+            matches!(event, DhcpEvent::LeaseDeclined(lease)
+                if lease.address == address && lease.lease_end == now)
+        });
+        assert!(declined);
+
+        let other = message(DhcpMessageType::Discover, [0x28, 0, 0, 0, 0, 2], None);
+        let slots = leases.pools[0].slots();
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            leases.offer_candidate(&other.identifier(), &slots, 0, later),
+            None
+        );
+    }
+
+    #[test]
+    fn declined_address_is_reused_after_the_hold_off() {
+        let mut leases = leases();
+        let now = Instant::from_secs(100);
+        leases.assignments[4] = DhcpAssignment::Declined {
+            hold_off_end_time: now + leases.decline_hold_off,
+        };
+        fill_except(&mut leases, 4, now + Duration::from_secs(60 * 60));
+
+        let other = message(DhcpMessageType::Discover, [0x28, 0, 0, 0, 0, 2], None);
+        let slots = leases.pools[0].slots();
+        let after_hold_off = now + leases.decline_hold_off;
+        assert_eq!(
+            leases.offer_candidate(&other.identifier(), &slots, 0, after_hold_off),
+            Some(4)
+        );
+    }
+
+    fn stored_lease(index: u16, identifier: &ClientId) -> StoredLease {
+        StoredLease {
+            index,
+            remaining_secs: 60,
+            identifier: Vec::from_slice(identifier.as_bytes()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn stored_lease_is_restored() {
+        let mut leases = leases();
+        let now = Instant::from_secs(100);
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        leases.restore_lease(stored_lease(2, &client.identifier()), now);
+        assert_eq!(leases.lease_index(&client.identifier(), now), Some(2));
+        assert_eq!(
+            leases.lease_info(2).unwrap().lease_end,
+            now + Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn stored_lease_outside_the_pools_is_dropped() {
+        // there are more slots than addresses in the pool
+        let mut leases: Leases<12> = leases_with(&[]);
+        let now = Instant::from_secs(100);
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        leases.restore_lease(stored_lease(11, &client.identifier()), now);
+        leases.restore_lease(stored_lease(40, &client.identifier()), now);
+        assert_eq!(leases.lease_index(&client.identifier(), now), None);
+    }
+
+    #[test]
+    fn stored_lease_on_an_address_reserved_for_another_client_is_dropped() {
+        let reservations = [Reservation {
+            key: ReservationKey::HardwareAddress(EthernetAddress([0x28, 0, 0, 0, 0, 2])),
+            address: Ipv4Address::new(192, 168, 1, 4),
+        }];
+        let mut leases: Leases<10> = leases_with(&reservations);
+        let now = Instant::from_secs(100);
+        let index = leases.address_index(reservations[0].address).unwrap();
+
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        leases.restore_lease(stored_lease(index as u16, &client.identifier()), now);
+        assert_eq!(leases.lease_index(&client.identifier(), now), None);
+
+        let reserved = message(DhcpMessageType::Request, [0x28, 0, 0, 0, 0, 2], None);
+        leases.restore_lease(stored_lease(index as u16, &reserved.identifier()), now);
+        assert_eq!(leases.lease_index(&reserved.identifier(), now), Some(index));
+    }
+
+    /// the value of a four byte option in a packet
+    fn u32_option(packet: &DhcpPacket<&[u8]>, kind: u8) -> Option<u32> {
+        packet
+            .options()
+            .find(|o| o.kind == kind)
+            .map(|o| u32::from_be_bytes(o.data.try_into().unwrap()))
+    }
+
+    #[test]
+    fn ack_carries_the_lease_renewal_and_rebinding_times() {
+        let leases = leases();
+        let request = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        let address = leases.slot_address(0).unwrap();
+        let ack = construct_ack(
+            SERVER_ADDRESS,
+            &request,
+            address,
+            leases.lease_times(0, None),
+        );
+        let mut buffer = [0; 1024];
+        let (len, _) = leases
+            .emit_reply(ack, &request, &[], Some(0), &mut buffer)
+            .unwrap();
+
+        let packet = DhcpPacket::new_checked(&buffer[..len]).unwrap();
+        let repr = DhcpRepr::parse(&packet).unwrap();
+        assert_eq!(repr.message_type, DhcpMessageType::Ack);
+        assert_eq!(repr.your_ip, address);
+        let lease = 60 * 60;
+        assert_eq!(repr.lease_duration, Some(lease));
+        // the lease time option
+        assert_eq!(u32_option(&packet, 51), Some(lease));
+        assert_eq!(u32_option(&packet, DHCP_OPT_RENEWAL_TIME), Some(lease / 2));
+        assert_eq!(
+            u32_option(&packet, DHCP_OPT_REBINDING_TIME),
+            Some(lease * 7 / 8)
+        );
+    }
+
+    const RELAY_AGENT_ADDRESS: Ipv4Address = Ipv4Address::new(10, 0, 0, 1);
+
+    #[test]
+    fn reply_to_a_relayed_message_goes_to_the_relay_agent() {
+        let discover = ClientMessage {
+            relay_agent_ip: RELAY_AGENT_ADDRESS,
+            ..message(DhcpMessageType::Discover, HARDWARE_ADDRESS, None)
+        };
+        let offer = construct_offer(
+            SERVER_ADDRESS,
+            &discover,
+            Ipv4Address::new(10, 0, 0, 10),
+            LeaseTimes {
+                lease: 3600,
+                renew: 1800,
+                rebind: 3150,
+            },
+        );
+        let destination = reply_destination(&offer);
+        assert_eq!(
+            destination,
+            ReplyDestination::RelayAgent(RELAY_AGENT_ADDRESS)
+        );
+        assert_eq!(
+            destination.endpoint(67, 68),
+            IpEndpoint::new(RELAY_AGENT_ADDRESS.into(), 67)
+        );
+    }
+
+    #[test]
+    fn nak_is_broadcast() {
+        let request = ClientMessage {
+            client_ip: Ipv4Address::new(192, 168, 1, 5),
+            ..message(DhcpMessageType::Request, HARDWARE_ADDRESS, None)
+        };
+        let nak = construct_nack(SERVER_ADDRESS, &request);
+        assert_eq!(reply_destination(&nak), ReplyDestination::Broadcast);
+        assert_eq!(
+            reply_destination(&nak).endpoint(67, 68),
+            IpEndpoint::new(Ipv4Address::BROADCAST.into(), 68)
+        );
+    }
+
+    #[test]
+    fn nak_identifies_the_server_and_leaves_the_addresses_empty() {
+        let request = ClientMessage {
+            client_ip: Ipv4Address::new(192, 168, 1, 5),
+            ..message(DhcpMessageType::Request, HARDWARE_ADDRESS, None)
+        };
+        let nak = construct_nack(SERVER_ADDRESS, &request);
+        assert_eq!(nak.server_identifier, Some(SERVER_ADDRESS));
+        assert!(nak.client_ip.is_unspecified());
+        assert!(nak.your_ip.is_unspecified());
+        assert!(nak.server_ip.is_unspecified());
+    }
+
+    #[test]
+    fn relayed_nak_has_the_broadcast_flag() {
+        let request = ClientMessage {
+            relay_agent_ip: RELAY_AGENT_ADDRESS,
+            ..message(DhcpMessageType::Request, HARDWARE_ADDRESS, None)
+        };
+        let nak = construct_nack(SERVER_ADDRESS, &request);
+        assert!(nak.broadcast);
+        assert_eq!(
+            reply_destination(&nak),
+            ReplyDestination::RelayAgent(RELAY_AGENT_ADDRESS)
+        );
+    }
+
+    #[test]
+    fn reply_to_a_client_with_an_address_is_unicast_to_it() {
+        let leases = leases();
+        let address = leases.slot_address(2).unwrap();
+        let request = ClientMessage {
+            client_ip: address,
+            ..message(DhcpMessageType::Request, HARDWARE_ADDRESS, None)
+        };
+        let ack = construct_ack(
+            SERVER_ADDRESS,
+            &request,
+            address,
+            leases.lease_times(0, None),
+        );
+        assert_eq!(reply_destination(&ack), ReplyDestination::Unicast(address));
+    }
+
+    #[test]
+    fn reply_is_broadcast_if_the_client_asked() {
+        let leases = leases();
+        let discover = ClientMessage {
+            broadcast: true,
+            ..message(DhcpMessageType::Discover, HARDWARE_ADDRESS, None)
+        };
+        let offer = construct_offer(
+            SERVER_ADDRESS,
+            &discover,
+            leases.slot_address(2).unwrap(),
+            leases.lease_times(0, None),
+        );
+        assert_eq!(reply_destination(&offer), ReplyDestination::Broadcast);
+    }
+
+    #[test]
+    fn reply_goes_to_the_client_if_it_did_not_ask_for_a_broadcast() {
+        let leases = leases();
+        let discover = message(DhcpMessageType::Discover, HARDWARE_ADDRESS, None);
+        let address = leases.slot_address(2).unwrap();
+        let offer = construct_offer(
+            SERVER_ADDRESS,
+            &discover,
+            address,
+            leases.lease_times(0, None),
+        );
+        assert_eq!(
+            reply_destination(&offer),
+            ReplyDestination::Client {
+                address,
+                hardware_address: EthernetAddress(HARDWARE_ADDRESS),
+            }
+        );
+    }
+
+    #[test]
+    fn renewing_client_is_acked() {
+        let mut leases = leases();
+        let now = Instant::from_secs(100);
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        assign(&mut leases, 2, &client, now + Duration::from_secs(60));
+
+        // a renewing client has its address in `client_ip`, no requested ip option, and a new
+        // transaction id
+        let renew = ClientMessage {
+            transaction_id: 2,
+            client_ip: leases.slot_address(2).unwrap(),
+            ..client
+        };
+        assert_eq!(leases.process_request(&renew, 0, now), Ok(Some(2)));
+        assert_eq!(
+            leases.lease_info(2).unwrap().lease_end,
+            now + Duration::from_secs(60 * 60)
+        );
+    }
+
+    #[test]
+    fn rebooting_client_asking_for_another_address_is_naked() {
+        let mut leases = leases();
+        let now = Instant::from_secs(100);
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        assign(&mut leases, 2, &client, now + Duration::from_secs(60));
+
+        let request = ClientMessage {
+            transaction_id: 2,
+            requested_ip: leases.slot_address(5),
+            ..client
+        };
+        assert_eq!(leases.process_request(&request, 0, now), Ok(None));
+    }
+
+    #[test]
+    fn rebooting_client_asking_for_an_address_outside_every_pool_is_naked() {
+        let mut leases = leases();
+        let client = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        let request = ClientMessage {
+            requested_ip: Some(Ipv4Address::new(10, 1, 2, 3)),
+            ..client
+        };
+        assert_eq!(
+            leases.process_request(&request, 0, Instant::from_secs(100)),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn client_behind_a_relay_agent_renews_straight_to_the_server() {
+        let relayed = PoolConfig {
+            pool: AddressPool::new(
+                Ipv4Address::new(10, 0, 0, 0),
+                24,
+                Ipv4Address::new(10, 0, 0, 10),
+                Ipv4Address::new(10, 0, 0, 19),
+            )
+            .unwrap(),
+            routers: &[RELAY_AGENT_ADDRESS],
+            ..pool_config()
+        };
+        let pools = [pool_config(), relayed];
+        let mut leases: Leases<20> = Leases::new(config(&pools, &[])).unwrap();
+        let now = Instant::from_secs(100);
+        let client = ClientMessage {
+            relay_agent_ip: RELAY_AGENT_ADDRESS,
+            ..message(DhcpMessageType::Request, HARDWARE_ADDRESS, None)
+        };
+        assert_eq!(leases.select_pool(&client), Some(1));
+        leases.assignments[12] = DhcpAssignment::Assigned {
+            transaction_id: client.transaction_id,
+            identifier: client.identifier(),
+            lease_end_time: now + Duration::from_secs(60),
+            hostname: None,
+        };
+
+        // the renewal comes straight from the client, so there's no relay agent address
+        let renew = ClientMessage {
+            transaction_id: 2,
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            client_ip: Ipv4Address::new(10, 0, 0, 12),
+            ..client
+        };
+        assert_eq!(leases.select_pool(&renew), Some(1));
+        assert_eq!(leases.process_request(&renew, 1, now), Ok(Some(12)));
+    }
+
+    /// a client identifier made from a DUID (RFC 4361), with the DUID padded out to `len`
+    fn duid_identifier(duid: u8, len: usize) -> std::vec::Vec<u8> {
+        let mut identifier = std::vec![255, 0, 0, 0, 1, 0, 4, duid];
+        identifier.resize(len, duid);
+        identifier
+    }
+
+    /// a discover carrying a client identifier option, as it is received
+    fn discover_packet(buffer: &mut [u8], client_identifier: &[u8]) -> usize {
+        let discover = message(DhcpMessageType::Discover, HARDWARE_ADDRESS, None);
+        let options = [DhcpOption {
+            kind: DHCP_OPT_CLIENT_IDENTIFIER,
+            data: client_identifier,
+        }];
+        let repr = DhcpRepr {
+            server_identifier: None,
+            additional_options: &options,
+            ..construct_packet_repr(
+                DhcpMessageType::Discover,
+                Ipv4Address::UNSPECIFIED,
+                &discover,
+                Ipv4Address::UNSPECIFIED,
+                Ipv4Address::UNSPECIFIED,
+                None,
+            )
+        };
+        repr.emit(&mut DhcpPacket::new_unchecked(&mut *buffer))
+            .unwrap();
+        repr.buffer_len()
+    }
+
+    #[test]
+    fn client_identifier_is_read_whole() {
+        let identifier = duid_identifier(1, 23);
+        let mut buffer = [0; 576];
+        let len = discover_packet(&mut buffer, &identifier);
+        let packet = DhcpPacket::new_checked(&buffer[..len]).unwrap();
+        let client_identifier = client_identifier(&packet).unwrap().unwrap();
+        assert_eq!(client_identifier.as_bytes(), identifier.as_slice());
+    }
+
+    #[test]
+    fn long_client_identifiers_are_kept_apart() {
+        // DUIDs can be up to 130 bytes, and these two only differ at the end
+        let mut first = duid_identifier(1, 130);
+        let mut second = first.clone();
+        *first.last_mut().unwrap() = 1;
+        *second.last_mut().unwrap() = 2;
+        let read = |identifier: &std::vec::Vec<u8>| {
+            let mut buffer = [0; 576];
+            let len = discover_packet(&mut buffer, identifier);
+            let packet = DhcpPacket::new_checked(&buffer[..len]).unwrap();
+            client_identifier(&packet).unwrap().unwrap()
+        };
+        let reservations = [Reservation {
+            key: ReservationKey::ClientIdentifier(first.clone().leak()),
+            address: Ipv4Address::new(192, 168, 1, 4),
+        }];
+        let (first, second) = (read(&first), read(&second));
+        assert_eq!(first.as_bytes().len(), MAX_CLIENT_ID_LEN);
+        assert_ne!(first, second);
+
+        // a reservation for the whole identifier is for the client it was read from, and a
+        // lease kept under the shortened identifier can be restored
+        assert!(reservations[0].is_for(&first));
+        assert!(!reservations[0].is_for(&second));
+        let mut leases: Leases<10> = leases_with(&reservations);
+        let index = leases.address_index(reservations[0].address).unwrap();
+        leases.restore_lease(stored_lease(index as u16, &first), Instant::from_secs(100));
+        assert!(leases.is_clients(index, &first));
+    }
+
+    #[test]
+    fn clients_sharing_a_hardware_address_get_separate_leases() {
+        let mut leases = leases();
+        let now = Instant::from_secs(100);
+        let slots = leases.pools[0].slots();
+        let mut leased = std::vec::Vec::new();
+        for duid in [1, 2] {
+            let identifier = duid_identifier(duid, 23);
+            let discover = message(
+                DhcpMessageType::Discover,
+                HARDWARE_ADDRESS,
+                Some(&identifier),
+            );
+            let index = leases
+                .offer_candidate(&discover.identifier(), &slots, 0, now)
+                .unwrap();
+            leases.offer(index, &discover, now).unwrap();
+            let request = ClientMessage {
+                message_type: DhcpMessageType::Request,
+                requested_ip: leases.slot_address(index),
+                server_identifier: Some(SERVER_ADDRESS),
+                ..discover
+            };
+            assert_eq!(leases.process_request(&request, 0, now), Ok(Some(index)));
+            leased.push((request.identifier(), index));
+        }
+        assert_ne!(leased[0].1, leased[1].1);
+        for (identifier, index) in leased {
+            assert_eq!(leases.lease_index(&identifier, now), Some(index));
+        }
+    }
+
+    #[test]
+    fn relay_agent_information_is_the_last_option() {
+        let leases = leases();
+        let information = [1, 4, 0xde, 0xad, 0xbe, 0xef];
+        let request = ClientMessage {
+            relay_agent_ip: Ipv4Address::new(192, 168, 1, 254),
+            relay_agent_information: Some(Vec::from_slice(&information).unwrap()),
+            ..message(DhcpMessageType::Request, HARDWARE_ADDRESS, None)
+        };
+        let ack = construct_ack(
+            SERVER_ADDRESS,
+            &request,
+            leases.slot_address(0).unwrap(),
+            leases.lease_times(0, None),
+        );
+        let mut buffer = [0; 1024];
+        let (len, _) = leases
+            .emit_reply(ack, &request, &[], Some(0), &mut buffer)
+            .unwrap();
+
+        let packet = DhcpPacket::new_checked(&buffer[..len]).unwrap();
+        let last = packet.options().last().unwrap();
+        assert_eq!(last.kind, DHCP_OPT_RELAY_AGENT_INFORMATION);
+        assert_eq!(last.data, &information);
+    }
+
+    #[test]
+    fn captive_portal_api_is_given_out() {
+        let pools = [pool_config()];
+        let leases: Leases<10> = Leases::new(DhcpConfig {
+            captive_portal_api: Some("https://portal.example.net/api/captive"),
+            ..config(&pools, &[])
+        })
+        .unwrap();
+        let request = message(DhcpMessageType::Request, HARDWARE_ADDRESS, None);
+        let ack = construct_ack(
+            SERVER_ADDRESS,
+            &request,
+            leases.slot_address(0).unwrap(),
+            leases.lease_times(0, None),
+        );
+        let mut buffer = [0; 1024];
+        let (len, _) = leases
+            .emit_reply(ack, &request, &[], Some(0), &mut buffer)
+            .unwrap();
+
+        let packet = DhcpPacket::new_checked(&buffer[..len]).unwrap();
+        let option = packet
+            .options()
+            .find(|option| option.kind == DHCP_OPT_CAPTIVE_PORTAL)
+            .unwrap();
+        assert_eq!(option.data, b"https://portal.example.net/api/captive");
+    }
+
+    #[test]
+    fn captive_portal_api_has_to_be_https() {
+        let pools = [pool_config()];
+        let leases: Option<Leases<10>> = Leases::new(DhcpConfig {
+            captive_portal_api: Some("http://portal.example.net/api/captive"),
+            ..config(&pools, &[])
+        });
+        assert!(leases.is_none());
+    }
+
+    #[test]
+    fn expired_lease_is_published_when_its_slot_is_offered_again() {
+        let mut leases = leases();
+        let mut events = DHCP_EVENTS.subscriber().unwrap();
+        let now = Instant::from_secs(100);
+        let old = message(DhcpMessageType::Request, [2, 0, 0, 0, 1, 5], None);
+        assign(&mut leases, 5, &old, now - Duration::from_secs(1));
+
+        let new = message(DhcpMessageType::Discover, [2, 0, 0, 0, 2, 5], None);
+        leases.offer(5, &new, now).unwrap();
+        assert!(leases.is_clients(5, &new.identifier()));
+        assert!(leases.leases_changed);
+
+        // other tests publish events at the same time, so only this one is looked for
+        let address = leases.slot_address(5).unwrap();
+        let expired = core::iter::from_fn(|| events.try_next_message_pure()).any(|event| {
+            matches!(event, DhcpEvent::LeaseExpired(lease)
+                if lease.address == address && lease.identifier == old.identifier())
+        });
+        assert!(expired);
+    }
+}
@@ -0,0 +1,285 @@
+use heapless::Vec;
+use smoltcp::wire::{DhcpOption, Error, Ipv4Address, Ipv4Cidr, Result};
+
+pub const DHCP_OPT_SUBNET_MASK: u8 = 1;
+pub const DHCP_OPT_ROUTER: u8 = 3;
+pub const DHCP_OPT_DOMAIN_NAME_SERVER: u8 = 6;
+pub const DHCP_OPT_HOST_NAME: u8 = 12;
+pub const DHCP_OPT_RENEWAL_TIME: u8 = 58;
+pub const DHCP_OPT_REBINDING_TIME: u8 = 59;
+pub const DHCP_OPT_VENDOR_CLASS_IDENTIFIER: u8 = 60;
+pub const DHCP_OPT_CLIENT_IDENTIFIER: u8 = 61;
+pub const DHCP_OPT_TFTP_SERVER_NAME: u8 = 66;
+pub const DHCP_OPT_BOOTFILE_NAME: u8 = 67;
+pub const DHCP_OPT_USER_CLASS: u8 = 77;
+pub const DHCP_OPT_CLIENT_FQDN: u8 = 81;
+pub const DHCP_OPT_RELAY_AGENT_INFORMATION: u8 = 82;
+pub const DHCP_OPT_CAPTIVE_PORTAL: u8 = 114;
+pub const DHCP_OPT_CLASSLESS_STATIC_ROUTE: u8 = 121;
+/// a code from the site-specific range, which no client sends and smoltcp skips over
+pub const DHCP_OPT_IGNORED: u8 = 254;
+
+/// the longest an option's data can be
+pub const MAX_OPTION_LEN: usize = u8::MAX as usize;
+
+/// the number of bytes available for storing configured options
+const REGISTRY_LEN: usize = 512;
+
+/// the number of bytes of options that can be added to a single reply
+const REPLY_OPTIONS_LEN: usize = 512;
+
+/// the most options that can be added to a single reply
+pub const MAX_REPLY_OPTIONS: usize = 32;
+
+/// the longest parameter request list that is kept from a client's message
+pub const MAX_PARAMETER_REQUESTS: usize = 64;
+
+/// the smallest dhcp message every client has to accept, from RFC 2132
+pub const MIN_MAX_MESSAGE_SIZE: u16 = 576;
+
+/// the size of the ip and udp headers, which count towards the maximum message size
+pub const IP_UDP_HEADER_LEN: usize = 20 + 8;
+
+/// A route to a network through a router, as given out in the classless static route option
+#[derive(Debug, Clone, Copy)]
+pub struct StaticRoute {
+    pub destination: Ipv4Cidr,
+    pub router: Ipv4Address,
+}
+
+impl StaticRoute {
+    /// whether this is a route for every address
+    pub fn is_default(&self) -> bool {
+        self.destination.prefix_len() == 0
+    }
+}
+
+/// The data of an option that holds a list of addresses, such as the router option.
+pub fn encode_addresses(addresses: &[Ipv4Address]) -> Result<Vec<u8, MAX_OPTION_LEN>> {
+    let mut data = Vec::new();
+    for address in addresses {
+        data.extend_from_slice(address.as_bytes())
+            .map_err(|_| Error)?;
+    }
+    Ok(data)
+}
+
+/// The data of the classless static route option (121). Each route is laid out as in
+/// RFC 3442: the prefix length, then only the octets of the destination that the prefix
+/// covers, then the router.
+pub fn encode_static_routes<'r>(
+    routes: impl Iterator<Item = &'r StaticRoute>,
+) -> Result<Vec<u8, MAX_OPTION_LEN>> {
+    let mut data = Vec::new();
+    for route in routes {
+        let prefix_len = route.destination.prefix_len();
+        let significant_octets = (prefix_len as usize).div_ceil(8);
+        let destination = route.destination.network().address();
+        data.push(prefix_len).map_err(|_| Error)?;
+        data.extend_from_slice(&destination.as_bytes()[..significant_octets])
+            .map_err(|_| Error)?;
+        data.extend_from_slice(route.router.as_bytes())
+            .map_err(|_| Error)?;
+    }
+    Ok(data)
+}
+
+/// Go through the options in a buffer of `kind, length, data` entries.
+fn iter_options(buffer: &[u8]) -> impl Iterator<Item = DhcpOption<'_>> {
+    let mut buffer = buffer;
+    core::iter::from_fn(move || {
+        let (&kind, rest) = buffer.split_first()?;
+        let (&len, rest) = rest.split_first()?;
+        let data = rest.get(..len as usize)?;
+        buffer = &rest[len as usize..];
+        Some(DhcpOption { kind, data })
+    })
+}
+
+/// The configuration options that the server gives out, keyed by option code.
+#[derive(Default)]
+pub struct OptionRegistry {
+    buffer: Vec<u8, REGISTRY_LEN>,
+}
+
+impl OptionRegistry {
+    /// Set the value of an option, replacing any value it already had.
+    /// The pad and end options can't be set, and neither can values over 255 bytes.
+    pub fn set(&mut self, kind: u8, data: &[u8]) -> Result<()> {
+        if kind == 0 || kind == 255 || data.len() > u8::MAX as usize {
+            return Err(Error);
+        }
+        let mut buffer = Vec::new();
+        for option in iter_options(&self.buffer).filter(|o| o.kind != kind) {
+            push_option(&mut buffer, option.kind, option.data)?;
+        }
+        push_option(&mut buffer, kind, data)?;
+        self.buffer = buffer;
+        Ok(())
+    }
+
+    pub fn get(&self, kind: u8) -> Option<&[u8]> {
+        iter_options(&self.buffer)
+            .find(|o| o.kind == kind)
+            .map(|o| o.data)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = DhcpOption<'_>> {
+        iter_options(&self.buffer)
+    }
+
+    /// Add the options to send to a client to `reply`. If the client sent a parameter request
+    /// list, that's the options on the list in the order they were asked for, otherwise it's
+    /// every configured option.
+    pub fn select(&self, parameter_request_list: Option<&[u8]>, reply: &mut ReplyOptions) {
+        match parameter_request_list {
+            Some(list) => {
+                for &kind in list {
+                    if let Some(data) = self.get(kind) {
+                        reply.push(kind, data);
+                    }
+                }
+            }
+            None => {
+                for option in self.iter() {
+                    reply.push(option.kind, option.data);
+                }
+            }
+        }
+    }
+}
+
+fn push_option<const N: usize>(buffer: &mut Vec<u8, N>, kind: u8, data: &[u8]) -> Result<()> {
+    buffer.push(kind).map_err(|_| Error)?;
+    buffer.push(data.len() as u8).map_err(|_| Error)?;
+    buffer.extend_from_slice(data).map_err(|_| Error)
+}
+
+/// The options that will be added to a single reply, limited to the space that the client
+/// can accept.
+pub struct ReplyOptions {
+    buffer: Vec<u8, REPLY_OPTIONS_LEN>,
+    budget: usize,
+    /// the bytes kept for the option that goes after all of the others, or 0 if there isn't one
+    reserved: usize,
+}
+
+impl ReplyOptions {
+    /// Create an empty set of options which can use up to `budget` bytes of the reply.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            budget,
+            reserved: 0,
+        }
+    }
+
+    /// Keep room for an option with `len` bytes of data, which is added with `push_last` once
+    /// all of the other options are in. Returns false if there isn't room for it.
+    pub fn reserve_last(&mut self, len: usize) -> bool {
+        let reserved = 2 + len;
+        if self.buffer.len() + reserved > self.budget.min(REPLY_OPTIONS_LEN)
+            || len > u8::MAX as usize
+        {
+            return false;
+        }
+        self.reserved = reserved;
+        true
+    }
+
+    /// Add the option that room was kept for with `reserve_last`.
+    pub fn push_last(&mut self, kind: u8, data: &[u8]) -> bool {
+        self.reserved = 0;
+        self.push(kind, data)
+    }
+
+    pub fn contains(&self, kind: u8) -> bool {
+        iter_options(&self.buffer).any(|o| o.kind == kind)
+    }
+
+    /// Add an option, if it hasn't been added already and there's room for it.
+    /// Returns whether the option is in the reply.
+    pub fn push(&mut self, kind: u8, data: &[u8]) -> bool {
+        if self.contains(kind) {
+            return true;
+        }
+        let len = 2 + data.len();
+        // the option kept for last takes up one of the entries as well as its bytes
+        let entries = iter_options(&self.buffer).count() + (self.reserved > 0) as usize;
+        if self.buffer.len() + len + self.reserved > self.budget
+            || data.len() > u8::MAX as usize
+            || entries >= MAX_REPLY_OPTIONS
+        {
+            return false;
+        }
+        push_option(&mut self.buffer, kind, data).is_ok()
+    }
+
+    pub fn options(&self) -> Vec<DhcpOption<'_>, MAX_REPLY_OPTIONS> {
+        iter_options(&self.buffer).take(MAX_REPLY_OPTIONS).collect()
+    }
+}
+
+/// The parts of a client's message that decide which options go in the reply.
+pub struct RequestedOptions {
+    /// the option codes the client asked for, in the order it asked for them
+    pub parameter_request_list: Option<Vec<u8, MAX_PARAMETER_REQUESTS>>,
+    /// the largest dhcp message the client will accept, including the ip and udp headers
+    pub max_message_size: u16,
+}
+
+impl RequestedOptions {
+    pub fn new(parameter_request_list: Option<&[u8]>, max_message_size: Option<u16>) -> Self {
+        Self {
+            parameter_request_list: parameter_request_list
+                .map(|list| list.iter().copied().take(MAX_PARAMETER_REQUESTS).collect()),
+            max_message_size: max_message_size
+                .unwrap_or(MIN_MAX_MESSAGE_SIZE)
+                .max(MIN_MAX_MESSAGE_SIZE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAST: u8 = DHCP_OPT_RELAY_AGENT_INFORMATION;
+
+    #[test]
+    fn option_kept_for_last_goes_after_the_others() {
+        let mut options = ReplyOptions::new(100);
+        assert!(options.reserve_last(4));
+        assert!(options.push(DHCP_OPT_SUBNET_MASK, &[255, 255, 255, 0]));
+        assert!(options.push(DHCP_OPT_ROUTER, &[192, 168, 1, 1]));
+        assert!(options.push_last(LAST, &[1, 2, 0xaa, 0xbb]));
+        let options = options.options();
+        assert_eq!(options.len(), 3);
+        assert_eq!(options[2].kind, LAST);
+        assert_eq!(options[2].data, &[1, 2, 0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn option_kept_for_last_keeps_its_room() {
+        // room for the last option and one more four byte option
+        let mut options = ReplyOptions::new(6 + 6);
+        assert!(options.reserve_last(4));
+        assert!(options.push(DHCP_OPT_SUBNET_MASK, &[255, 255, 255, 0]));
+        assert!(!options.push(DHCP_OPT_ROUTER, &[192, 168, 1, 1]));
+        assert!(options.push_last(LAST, &[1, 2, 0xaa, 0xbb]));
+        assert_eq!(options.options().last().map(|o| o.kind), Some(LAST));
+    }
+
+    #[test]
+    fn option_kept_for_last_keeps_its_entry() {
+        let mut options = ReplyOptions::new(REPLY_OPTIONS_LEN);
+        assert!(options.reserve_last(4));
+        for kind in 1..=MAX_REPLY_OPTIONS as u8 + 8 {
+            options.push(kind + 100, &[]);
+        }
+        assert!(options.push_last(LAST, &[1, 2, 0xaa, 0xbb]));
+        let options = options.options();
+        assert_eq!(options.len(), MAX_REPLY_OPTIONS);
+        assert_eq!(options.last().map(|o| o.kind), Some(LAST));
+    }
+}
@@ -1,242 +1,297 @@
+use core::cell::RefCell;
 use defmt::unwrap;
+use embassy_futures::select::{select4, Either4};
+use embassy_net::raw::{PacketMetadata as RawPacketMetadata, RawSocket};
 use embassy_net::udp::{PacketMetadata, UdpSocket};
-use embassy_time::{Duration, Instant};
-use heapless::Vec;
+use embassy_rp::flash::{Blocking, Flash};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage::nor_flash::NorFlash;
 use smoltcp::wire::{
-    DhcpMessageType, DhcpOption, DhcpPacket, DhcpRepr, Error, EthernetAddress, IpEndpoint,
-    Ipv4Address, Result,
+    DhcpMessageType, DhcpOption, DhcpPacket, DhcpRepr, Error, IpProtocol, IpVersion, Ipv4Address,
+    Result,
 };
 
+use pico_dhcp_dns_server::access_control::{AccessConfig, HardwareAddressMatch};
+use pico_dhcp_dns_server::address_pool::AddressPool;
+use pico_dhcp_dns_server::client_class::ClientClass;
+use pico_dhcp_dns_server::client_id::ClientId;
+use pico_dhcp_dns_server::dhcp_leases::{
+    client_identifier, construct_ack, construct_inform_ack, construct_nack, construct_offer,
+    rename_option, ClientMessage, DhcpConfig, Leases, PoolConfig, Reservation,
+    DEFAULT_REBIND_RATIO, DEFAULT_RENEW_RATIO,
+};
+use pico_dhcp_dns_server::dhcp_options::{
+    DHCP_OPT_CLIENT_FQDN, DHCP_OPT_CLIENT_IDENTIFIER, DHCP_OPT_IGNORED,
+};
+use pico_dhcp_dns_server::lease_storage::{LeaseStorage, MAX_STORED_LEASES};
+use pico_dhcp_dns_server::rate_limit::{ClientRateLimiter, PacketCeiling, RateLimit};
+
+use crate::conflict_probe::ConflictProbe;
+
 pub const HOSTNAME: &str = "piconet.local";
 
+/// options given out alongside the router, subnet mask and dns servers on the local network
 const OPTIONS: &[DhcpOption<'static>] = &[DhcpOption {
     kind: 15,
     data: HOSTNAME.as_bytes(),
 }];
 
-#[derive(Debug)]
-enum DhcpAssignment {
-    Offered {
-        transaction_id: u32,
-        identifier: EthernetAddress,
-    },
-    Assigned {
-        transaction_id: u32,
-        identifier: EthernetAddress,
-        lease_end_time: Instant,
-    },
-    Free,
+/// how often the leases are written to flash, if they have changed
+const LEASE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// how often expired offers and leases are swept out of the assignments
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// how often the server's statistics are logged
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Counts of what the server has done with the messages it received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhcpStats {
+    pub received: u32,
+    /// messages dropped because their client was over its rate limit
+    pub dropped_client_rate: u32,
+    /// messages dropped because the server was over its packets per second ceiling
+    pub dropped_global_rate: u32,
 }
 
+static DHCP_STATS: Mutex<CriticalSectionRawMutex, RefCell<DhcpStats>> =
+    Mutex::new(RefCell::new(DhcpStats {
+        received: 0,
+        dropped_client_rate: 0,
+        dropped_global_rate: 0,
+    }));
+
+/// the server's statistics so far
+pub fn dhcp_stats() -> DhcpStats {
+    DHCP_STATS.lock(|stats| *stats.borrow())
+}
+
+fn update_stats(update: impl FnOnce(&mut DhcpStats)) {
+    DHCP_STATS.lock(|stats| update(&mut stats.borrow_mut()))
+}
+
+/// the size of the pico's flash
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// the leases are stored in the last 16k of flash, which is kept out of the
+/// firmware image in memory.x
+const LEASE_STORAGE_START: u32 = (FLASH_SIZE - 16 * 1024) as u32;
+
+/// the number of clients whose message rates are kept track of at once
+const MAX_RATE_LIMITED_CLIENTS: usize = 16;
+
+/// addresses that are always given to the same client.
+/// add entries here to pin a device to an address in the pool, for example
+/// ```ignore
+/// Reservation {
+///     key: ReservationKey::HardwareAddress(EthernetAddress([0x28, 0xcd, 0xc1, 0, 0, 1])),
+///     address: Ipv4Address::new(169, 254, 1, 10),
+/// }
+/// ```
+const RESERVATIONS: &[Reservation] = &[];
+
+/// clients that are always turned away, for example
+/// `HardwareAddressMatch::Address(EthernetAddress([0x28, 0xcd, 0xc1, 0x00, 0x00, 0x02]))`
+/// or every device from one manufacturer with `HardwareAddressMatch::Oui([0x28, 0xcd, 0xc1])`
+const DENY_LIST: &[HardwareAddressMatch] = &[];
+
+/// if there are entries here, only these clients and the ones with reservations get addresses
+const ALLOW_LIST: &[HardwareAddressMatch] = &[];
+
+/// families of devices that get their own configuration.
+/// add entries here to give a kind of device its own options or lease time, for example
+/// ```ignore
+/// ClientClass {
+///     name: "sensors",
+///     matches: ClassMatch::VendorClass(b"espressif"),
+///     lease_time: Some(Duration::from_secs(24 * 60 * 60)),
+///     options: &[],
+/// }
+/// ```
+const CLASSES: &[ClientClass<'static>] = &[];
+
+/// The https uri the captive portal api (RFC 8908) is served on, which clients are given in
+/// option 114. The web server here only speaks plain http, so this is left out until the api is
+/// served over https, for example `Some("https://portal.example.net/api/captive")`.
+const CAPTIVE_PORTAL_API: Option<&str> = None;
+
 struct DhcpServer<
     'a,
+    F: NorFlash,
     const N_ADDRESSES: usize,
     const SERVER_PORT: u16,
     const CLIENT_PORT: u16,
     const DATA_BUFFER_LEN: usize,
 > {
-    server_address: Ipv4Address,
-    assignments: [DhcpAssignment; N_ADDRESSES],
+    leases: Leases<N_ADDRESSES>,
     socket: UdpSocket<'a>,
     data_buffer: [u8; DATA_BUFFER_LEN],
-    lease_time: Duration,
+    probe: ConflictProbe<'a>,
+    probe_timeout: Option<Duration>,
+    client_rate_limiter: ClientRateLimiter<ClientId, MAX_RATE_LIMITED_CLIENTS>,
+    packet_ceiling: PacketCeiling,
+    storage: LeaseStorage<F>,
 }
 
 impl<
         'a,
+        F: NorFlash,
         const N_ADDRESSES: usize,
         const SERVER_PORT: u16,
         const CLIENT_PORT: u16,
         const DATA_BUFFER_LEN: usize,
-    > DhcpServer<'a, N_ADDRESSES, SERVER_PORT, CLIENT_PORT, DATA_BUFFER_LEN>
+    > DhcpServer<'a, F, N_ADDRESSES, SERVER_PORT, CLIENT_PORT, DATA_BUFFER_LEN>
 {
-    fn construct_packet_repr(
-        message_type: DhcpMessageType,
-        server_ip: Ipv4Address,
-        client_ip: Ipv4Address,
-        client_hardware_address: EthernetAddress,
-        transaction_id: u32,
-        assigned_address: Ipv4Address,
-        lease_duration_seconds: Option<u32>,
-    ) -> DhcpRepr<'a> {
-        DhcpRepr {
-            message_type,
-            transaction_id,
-            secs: 0,
-            client_hardware_address,
-            client_ip,
-            your_ip: assigned_address,
-            server_ip,
-            router: Some(server_ip),
-            subnet_mask: Some(Ipv4Address::new(255, 255, 255, 0)),
-            relay_agent_ip: Ipv4Address::new(0, 0, 0, 0),
-            broadcast: false,
-            requested_ip: None,
-            client_identifier: None,
-            server_identifier: Some(server_ip),
-            parameter_request_list: None,
-            dns_servers: Some(unwrap!(Vec::from_slice(&[server_ip]))),
-            max_size: None,
-            lease_duration: lease_duration_seconds,
-            renew_duration: None,
-            rebind_duration: None,
-            additional_options: OPTIONS,
-        }
-    }
-
-    fn construct_offer(
-        server_ip: Ipv4Address,
-        client_hardware_address: EthernetAddress,
-        transaction_id: u32,
-        assigned_address: Ipv4Address,
-        lease_duration_seconds: u32,
-    ) -> DhcpRepr<'a> {
-        Self::construct_packet_repr(
-            DhcpMessageType::Offer,
-            server_ip,
-            Ipv4Address::new(0, 0, 0, 0),
-            client_hardware_address,
-            transaction_id,
-            assigned_address,
-            Some(lease_duration_seconds),
-        )
-    }
-
-    fn construct_ack(
-        server_ip: Ipv4Address,
-        client_hardware_address: EthernetAddress,
-        client_ip: Ipv4Address,
-        transaction_id: u32,
-        address: Ipv4Address,
-    ) -> DhcpRepr<'a> {
-        Self::construct_packet_repr(
-            DhcpMessageType::Ack,
-            server_ip,
-            client_ip,
-            client_hardware_address,
-            transaction_id,
-            address,
-            None,
-        )
+    /// Emit a reply to `message` into the data buffer and send it to wherever
+    /// `reply_destination` says. See `Leases::emit_reply` for what goes in it.
+    async fn send_reply(
+        &mut self,
+        packet_repr: DhcpRepr<'_>,
+        message: &ClientMessage,
+        reply_to: &[DhcpOption<'_>],
+        pool: Option<usize>,
+    ) -> Result<()> {
+        let (len, destination) =
+            self.leases
+                .emit_reply(packet_repr, message, reply_to, pool, &mut self.data_buffer)?;
+        self.socket
+            .send_to(
+                &self.data_buffer[..len],
+                destination.endpoint(SERVER_PORT, CLIENT_PORT),
+            )
+            .await
+            .map_err(|_| smoltcp::wire::Error)?;
+        Ok(())
     }
 
-    fn construct_nack(
-        client_hardware_address: EthernetAddress,
-        client_ip: Ipv4Address,
-        transaction_id: u32,
-    ) -> DhcpRepr<'a> {
-        Self::construct_packet_repr(
-            DhcpMessageType::Nak,
-            Ipv4Address::new(0, 0, 0, 0),
-            client_ip,
-            client_hardware_address,
-            transaction_id,
-            Ipv4Address::new(0, 0, 0, 0),
-            None,
-        )
+    /// the client fqdn option for a reply, if the client sent one
+    fn fqdn_option(data: Option<&[u8]>) -> Option<DhcpOption<'_>> {
+        data.map(|data| DhcpOption {
+            kind: DHCP_OPT_CLIENT_FQDN,
+            data,
+        })
     }
 
     async fn construct_and_send_offer(
         &mut self,
-        client_hardware_address: EthernetAddress,
-        transaction_id: u32,
-        index: u8,
+        message: &ClientMessage,
+        pool: usize,
+        index: usize,
     ) -> Result<()> {
-        let mut packet = DhcpPacket::new_checked(&mut self.data_buffer)?;
-        let address = Self::construct_address(index);
-        let packet_repr = Self::construct_offer(
-            self.server_address,
-            client_hardware_address,
-            transaction_id,
+        let address = self.leases.slot_address(index).ok_or(Error)?;
+        let packet_repr = construct_offer(
+            self.leases.server_address(),
+            message,
             address,
-            self.lease_time.as_secs() as u32,
+            self.leases.lease_times(pool, message.class),
         );
-        let len = packet_repr.buffer_len();
-
-        packet_repr.emit(&mut packet)?;
-        self.socket
-            .send_to(
-                &self.data_buffer[..len],
-                IpEndpoint::new(Ipv4Address::BROADCAST.into(), CLIENT_PORT),
-            )
+        // the name isn't made unique until the client takes the lease, so the offer only
+        // carries the name the client asked for
+        let fqdn = message
+            .client_fqdn
+            .as_ref()
+            .map(|fqdn| fqdn.reply(message.hostname.as_ref(), HOSTNAME));
+        let options = Self::fqdn_option(fqdn.as_deref());
+        self.send_reply(packet_repr, message, options.as_slice(), Some(pool))
             .await
-            .map_err(|_| smoltcp::wire::Error)?;
-        Ok(())
     }
 
     async fn construct_and_send_ack(
         &mut self,
-        client_hardware_address: EthernetAddress,
-        transaction_id: u32,
-        index: u8,
-        client_ip: Ipv4Address,
+        message: &ClientMessage,
+        pool: usize,
+        index: usize,
     ) -> Result<()> {
-        let mut packet = DhcpPacket::new_checked(&mut self.data_buffer)?;
-        let address = Self::construct_address(index);
-        let packet_repr = Self::construct_ack(
-            self.server_address,
-            client_hardware_address,
-            client_ip,
-            transaction_id,
+        let address = self.leases.slot_address(index).ok_or(Error)?;
+        let packet_repr = construct_ack(
+            self.leases.server_address(),
+            message,
             address,
+            self.leases.lease_times(pool, message.class),
         );
-        let len = packet_repr.buffer_len();
-
-        packet_repr.emit(&mut packet)?;
-        self.socket
-            .send_to(
-                &self.data_buffer[..len],
-                IpEndpoint::new(Ipv4Address::BROADCAST.into(), CLIENT_PORT),
-            )
+        let fqdn = message
+            .client_fqdn
+            .as_ref()
+            .map(|fqdn| fqdn.reply(self.leases.hostname(index), HOSTNAME));
+        let options = Self::fqdn_option(fqdn.as_deref());
+        self.send_reply(packet_repr, message, options.as_slice(), Some(pool))
             .await
-            .map_err(|_| smoltcp::wire::Error)?;
-        Ok(())
     }
 
-    async fn construct_and_send_nack(
+    async fn construct_and_send_inform_ack(
         &mut self,
-        client_hardware_address: EthernetAddress,
-        transaction_id: u32,
-        client_ip: Ipv4Address,
+        message: &ClientMessage,
+        pool: usize,
     ) -> Result<()> {
-        let mut packet = DhcpPacket::new_checked(&mut self.data_buffer)?;
-        let packet_repr = Self::construct_nack(client_hardware_address, client_ip, transaction_id);
-        let len = packet_repr.buffer_len();
+        let packet_repr = construct_inform_ack(self.leases.server_address(), message);
+        self.send_reply(packet_repr, message, &[], Some(pool)).await
+    }
 
-        packet_repr.emit(&mut packet)?;
-        self.socket
-            .send_to(
-                &self.data_buffer[..len],
-                IpEndpoint::new(Ipv4Address::BROADCAST.into(), CLIENT_PORT),
-            )
-            .await
-            .map_err(|_| smoltcp::wire::Error)?;
-        Ok(())
+    async fn construct_and_send_nack(&mut self, message: &ClientMessage) -> Result<()> {
+        let packet_repr = construct_nack(self.leases.server_address(), message);
+        // a nak doesn't configure anything
+        self.send_reply(packet_repr, message, &[], None).await
     }
 
     fn new(
         mut socket: UdpSocket<'a>,
-        server_address: Ipv4Address,
-        lease_time: Duration,
+        probe: ConflictProbe<'a>,
+        config: DhcpConfig<'_>,
+        storage: LeaseStorage<F>,
     ) -> Option<Self> {
-        if socket.endpoint().is_specified() {
+        let DhcpConfig {
+            probe_timeout,
+            client_rate_limit,
+            max_packets_per_second,
+            ..
+        } = config;
+        // every lease has to fit in a snapshot for them all to be kept over a reboot
+        if socket.endpoint().is_specified() || N_ADDRESSES > MAX_STORED_LEASES {
             None
         } else {
+            let leases = Leases::new(config)?;
             socket.bind(SERVER_PORT).ok()?;
-            Some(Self {
-                lease_time,
-                server_address,
-                assignments: [(); N_ADDRESSES].map(|_| DhcpAssignment::Free),
+            let mut server = Self {
+                leases,
                 socket,
                 data_buffer: [0u8; DATA_BUFFER_LEN],
-            })
+                probe,
+                probe_timeout,
+                client_rate_limiter: ClientRateLimiter::new(client_rate_limit),
+                packet_ceiling: PacketCeiling::new(max_packets_per_second),
+                storage,
+            };
+            server.restore_leases();
+            Some(server)
         }
     }
 
-    /// this function constructs the addresses that we'll use.
-    /// TODO: probably update this???
-    fn construct_address(i: u8) -> Ipv4Address {
-        Ipv4Address::new(169, 254, 1, i + 2)
+    /// Load the leases that were stored in flash before the last reboot.
+    fn restore_leases(&mut self) {
+        let now = Instant::now();
+        let Self {
+            storage, leases, ..
+        } = self;
+        let result = storage.load(|lease| leases.restore_lease(lease, now));
+        if result.is_err() {
+            log::warn!("Error reading leases from flash");
+        }
+    }
+
+    /// Write the current leases to flash, if they have changed since the last time.
+    fn save_leases(&mut self) {
+        if !self.leases.leases_changed() {
+            return;
+        }
+        match self
+            .storage
+            .store(self.leases.stored_leases(Instant::now()))
+        {
+            Ok(()) => self.leases.leases_saved(),
+            Err(_) => log::warn!("Error writing leases to flash"),
+        }
     }
 
     /// Given a discover message from a client, go through the list of addresses.
@@ -248,176 +303,187 @@ impl<
     /// new transaction ID
     /// If there is a free spot, create an offer in that spot and send out an offer.
     /// If there are no free spots, use the first different ID offer found.
-    async fn process_discover(
-        &mut self,
-        client_hardware_address: EthernetAddress,
-        client_identifier: Option<EthernetAddress>,
-        transaction_id: u32,
-    ) -> Result<()> {
-        let id = client_identifier.unwrap_or(client_hardware_address);
-        let mut first_offered_index = None;
-        for (index, assignment) in self.assignments.iter_mut().enumerate() {
-            match assignment {
-                // if the lease has been offered to another index, then this can possibly be taken
-                // if there are no more spots
-                DhcpAssignment::Offered {
-                    identifier: a_identifier,
-                    ..
-                } if a_identifier != &id => first_offered_index = Some(index),
-                // if the assignment is given to a different identifier, and the lease time on that assignment is not up yet, then this spot can't be used
-                DhcpAssignment::Assigned {
-                    identifier: a_identifier,
-                    lease_end_time,
-                    ..
-                } if (*a_identifier != id) && (Instant::now() < *lease_end_time) => {}
-                _ => {
-                    // we are free to use this assignment spot
-                    *assignment = DhcpAssignment::Offered {
-                        transaction_id,
-                        identifier: id,
-                    };
-                    return self
-                        .construct_and_send_offer(
-                            client_hardware_address,
-                            transaction_id,
-                            index as u8,
-                        )
-                        .await;
-                }
+    /// Offers that have expired are treated as free spots.
+    /// See `offer_candidate` for the order the spots are looked at in.
+    /// Before an address is offered, it is probed, and if something answers it is marked as
+    /// conflicted and the next one is tried.
+    /// Only the addresses in the client's pool are looked at.
+    /// Clients with a reservation in the pool are only ever offered their reserved address,
+    /// which is probed the same way, and reserved addresses are never offered to anyone else.
+    async fn process_discover(&mut self, message: &ClientMessage, pool: usize) -> Result<()> {
+        if !self.leases.is_allowed(message) {
+            log::info!(
+                "Not offering an address to {}, which isn't allowed one",
+                message.client_hardware_address
+            );
+            return Ok(());
+        }
+        let id = message.identifier();
+        let now = Instant::now();
+        let slots = self.leases.pool_slots(pool);
+        if let Some(index) = self
+            .leases
+            .reserved_index(
+                message.client_hardware_address,
+                message.client_identifier.as_ref(),
+            )
+            .filter(|index| slots.contains(index))
+        {
+            if self.leases.is_declined(index, now) {
+                log::warn!("Reserved address is declined, not offering it");
+                return Ok(());
             }
+            if !self.leases.is_clients(index, &id) && self.is_in_use(index).await {
+                self.leases.mark_conflicted(index, Instant::now())?;
+                return Ok(());
+            }
+            self.leases.offer(index, message, now)?;
+            return self.construct_and_send_offer(message, pool, index).await;
         }
-        if let Some(i) = first_offered_index {
-            *unsafe { self.assignments.get_unchecked_mut(i) } = DhcpAssignment::Offered {
-                identifier: id,
-                transaction_id,
-            };
-            self.construct_and_send_offer(client_hardware_address, transaction_id, i as u8)
-                .await
-        } else {
-            Ok(())
+        while let Some(index) = self.leases.offer_candidate(&id, &slots, pool, now) {
+            // an address the client already has would be answered by the client itself
+            if !self.leases.is_clients(index, &id) && self.is_in_use(index).await {
+                self.leases.mark_conflicted(index, Instant::now())?;
+                continue;
+            }
+            self.leases.offer(index, message, now)?;
+            return self.construct_and_send_offer(message, pool, index).await;
         }
+        Ok(())
     }
 
-    async fn process_request(
-        &mut self,
-        client_ip: Ipv4Address,
-        client_hardware_address: EthernetAddress,
-        client_identifier: Option<EthernetAddress>,
-        transaction_id: u32,
-        requested_ip: Option<Ipv4Address>,
-    ) -> Result<()> {
-        let id = client_identifier.unwrap_or(client_hardware_address);
-        let new_lease_time = Instant::now()
-            .checked_add(self.lease_time)
-            .ok_or(smoltcp::wire::Error)?;
-        if let Some(address) = requested_ip {
-            let address_index = address.0[3].checked_sub(2).ok_or(Error)?;
-
-            match self
-                .assignments
-                .get_mut(address_index as usize)
-                .ok_or(Error)?
-            {
-                ref assignment @ DhcpAssignment::Offered { identifier, .. }
-                    if *identifier == id =>
-                {
-                    *assignment = DhcpAssignment::Assigned {
-                        transaction_id,
-                        identifier: id,
-                        lease_end_time: new_lease_time,
-                    };
-                }
-                DhcpAssignment::Assigned {
-                    transaction_id: tid,
-                    identifier,
-                    lease_end_time,
-                } if *identifier == id => {
-                    *tid = transaction_id;
-                    *lease_end_time = new_lease_time;
-                }
-                assignment @ DhcpAssignment::Free => {}
-                _ => {}
+    /// Check whether something already answers on the address in a slot, if probing is on.
+    async fn is_in_use(&mut self, index: usize) -> bool {
+        let (Some(timeout), Some(address)) = (self.probe_timeout, self.leases.slot_address(index))
+        else {
+            return false;
+        };
+        let in_use = self
+            .probe
+            .is_in_use(self.leases.server_address(), address, timeout)
+            .await;
+        if in_use {
+            log::warn!(
+                "Address {} is already in use, marking it as conflicted",
+                address
+            );
+        }
+        in_use
+    }
+
+    /// Given a request message from a client, ack or nak it. Clients asking for an address from
+    /// a pool other than the one for their subnet are sent a nak, as are clients that aren't
+    /// allowed an address unless the server ignores them.
+    async fn process_request(&mut self, message: &ClientMessage, pool: usize) -> Result<()> {
+        if !self.leases.is_allowed(message) {
+            log::info!(
+                "Refusing a request from {}, which isn't allowed an address",
+                message.client_hardware_address
+            );
+            return if self.leases.ignores_unknown_clients() {
+                Ok(())
+            } else {
+                self.construct_and_send_nack(message).await
             };
         }
-        let mut assigned_index = None;
-        for (i, assignment) in self.assignments.iter_mut().enumerate() {
-            match assignment {
-                // we have offered an assignment with this transaction_id to this identifier
-                DhcpAssignment::Offered {
-                    identifier: a_identifier,
-                    transaction_id: a_transaction_id,
-                } if (*a_identifier == id) && (*a_transaction_id == transaction_id) => {
-                    *assignment = DhcpAssignment::Assigned {
-                        identifier: id,
-                        lease_end_time: new_lease_time,
-                        transaction_id,
-                    };
-                    assigned_index = Some(i);
-                    break;
-                }
-                // we have already assigned an ip to this identifier with this transaction_id, and the
-                // lease time isn't up
-                DhcpAssignment::Assigned {
-                    identifier: a_identifier,
-                    transaction_id: a_transaction_id,
-                    lease_end_time,
-                } if (*a_identifier == id)
-                    && (*a_transaction_id == transaction_id)
-                    && (Instant::now() < *lease_end_time) =>
-                {
-                    *lease_end_time = new_lease_time;
-                    assigned_index = Some(i);
-                    break;
-                }
-                _ => {}
-            }
+        match self.leases.process_request(message, pool, Instant::now())? {
+            Some(index) => self.construct_and_send_ack(message, pool, index).await,
+            None => self.construct_and_send_nack(message).await,
         }
-        if let Some(i) = assigned_index {
-            self.construct_and_send_ack(client_hardware_address, transaction_id, i as u8, client_ip)
-                .await
-        } else {
-            self.construct_and_send_nack(client_hardware_address, transaction_id, client_ip)
-                .await
+    }
+
+    /// Given an inform message from a client, which has configured its own address,
+    /// reply with an ack containing the rest of the configuration. The reply is unicast
+    /// to the address the client says it has, so we can't answer if it didn't give one.
+    async fn process_inform(&mut self, message: &ClientMessage, pool: usize) -> Result<()> {
+        if message.client_ip.is_unspecified() {
+            return Err(Error);
         }
+        self.construct_and_send_inform_ack(message, pool).await
     }
 
-    async fn process_packet(&mut self) -> Result<()> {
-        let packet = DhcpPacket::new_checked(&self.data_buffer)?;
+    /// Handle the message of `len` bytes at the start of the data buffer. Messages over the
+    /// server's ceiling are dropped before they're parsed, and messages from a client over its
+    /// rate limit are dropped before they can change any assignments.
+    async fn process_packet(&mut self, len: usize) -> Result<()> {
+        let now = Instant::now();
+        update_stats(|stats| stats.received += 1);
+        if !self.packet_ceiling.try_take(now) {
+            update_stats(|stats| stats.dropped_global_rate += 1);
+            return Ok(());
+        }
+        let data = &mut self.data_buffer[..len];
+        let Ok(client_identifier) = client_identifier(&DhcpPacket::new_checked(&data[..])?) else {
+            log::warn!("Client identifier is empty, ignoring the message");
+            return Err(Error);
+        };
+        // smoltcp rejects the whole packet if a 7 byte client identifier isn't an ethernet
+        // address, so now that the identifier has been read, hide it from the parser
+        rename_option(data, DHCP_OPT_CLIENT_IDENTIFIER, DHCP_OPT_IGNORED);
+        let packet = DhcpPacket::new_checked(&data[..])?;
         let packet_repr = DhcpRepr::parse(&packet)?;
-        match packet_repr.message_type {
-            DhcpMessageType::Discover => {
-                self.process_discover(
-                    packet_repr.client_hardware_address,
-                    packet_repr.client_identifier,
-                    packet_repr.transaction_id,
-                )
-                .await
-            }
-            DhcpMessageType::Request => {
-                self.process_request(
-                    packet_repr.client_ip,
-                    packet_repr.client_hardware_address,
-                    packet_repr.client_identifier,
-                    packet_repr.transaction_id,
-                    packet_repr.requested_ip,
-                )
-                .await
-            }
+        let mut message = ClientMessage::new(&packet, &packet_repr, client_identifier);
+        if !self.client_rate_limiter.try_take(message.identifier(), now) {
+            update_stats(|stats| stats.dropped_client_rate += 1);
+            return Ok(());
+        }
+        message.class = self.leases.classify(&message);
+        let Some(pool) = self.leases.select_pool(&message) else {
+            log::warn!("No pool for the client's subnet or class, ignoring the message");
+            return Err(Error);
+        };
+        match message.message_type {
+            DhcpMessageType::Discover => self.process_discover(&message, pool).await,
+            DhcpMessageType::Request => self.process_request(&message, pool).await,
+            DhcpMessageType::Release => self.leases.process_release(&message, now),
+            DhcpMessageType::Decline => self.leases.process_decline(&message, now),
+            DhcpMessageType::Inform => self.process_inform(&message, pool).await,
             _ => Ok(()),
         }
     }
 
     async fn run(&mut self) -> ! {
+        let mut next_save = Instant::now() + LEASE_SAVE_INTERVAL;
+        let mut next_sweep = Instant::now() + SWEEP_INTERVAL;
+        let mut next_stats = Instant::now() + STATS_LOG_INTERVAL;
         loop {
-            match self.socket.recv_from(&mut self.data_buffer).await {
-                Ok((_, _)) => {
-                    if let Err(_) = self.process_packet().await {
+            match select4(
+                self.socket.recv_from(&mut self.data_buffer),
+                Timer::at(next_save),
+                Timer::at(next_sweep),
+                Timer::at(next_stats),
+            )
+            .await
+            {
+                Either4::First(Ok((len, _))) => {
+                    if let Err(_) = self.process_packet(len).await {
                         log::warn!("Error processing dhcp packet!!!");
                     }
+                    self.leases.publish_leases(Instant::now());
                 }
-                Err(_) => {
+                Either4::First(Err(_)) => {
                     log::info!("Error receiving data")
                 }
+                Either4::Second(()) => {
+                    self.save_leases();
+                    next_save += LEASE_SAVE_INTERVAL;
+                }
+                Either4::Third(()) => {
+                    let now = Instant::now();
+                    self.leases.sweep_assignments(now);
+                    self.leases.publish_leases(now);
+                    next_sweep += SWEEP_INTERVAL;
+                }
+                Either4::Fourth(()) => {
+                    let stats = dhcp_stats();
+                    log::info!(
+                        "{} dhcp messages, {} dropped by client rate limits, {} by the ceiling",
+                        stats.received,
+                        stats.dropped_client_rate,
+                        stats.dropped_global_rate
+                    );
+                    next_stats += STATS_LOG_INTERVAL;
+                }
             }
         }
     }
@@ -427,6 +493,7 @@ impl<
 pub async fn dhcp_server_task(
     stack: &'static embassy_net::Stack<cyw43::NetDriver<'static>>,
     assigned_address: Ipv4Address,
+    flash: embassy_rp::peripherals::FLASH,
 ) -> ! {
     let mut rx_meta = [PacketMetadata::EMPTY; 1024];
     let mut rx_buffer = [0; 1024];
@@ -441,10 +508,77 @@ pub async fn dhcp_server_task(
         &mut tx_buffer,
     );
 
-    let mut server: DhcpServer<'_, 10, 67, 68, 2048> = unwrap!(DhcpServer::new(
+    let mut probe_rx_meta = [RawPacketMetadata::EMPTY; 4];
+    let mut probe_rx_buffer = [0; 512];
+    let mut probe_tx_meta = [RawPacketMetadata::EMPTY; 4];
+    let mut probe_tx_buffer = [0; 512];
+
+    let probe_socket = RawSocket::new(
+        stack,
+        IpVersion::Ipv4,
+        IpProtocol::Icmp,
+        &mut probe_rx_meta,
+        &mut probe_rx_buffer,
+        &mut probe_tx_meta,
+        &mut probe_tx_buffer,
+    );
+
+    let flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(flash);
+    let storage = unwrap!(LeaseStorage::new(
+        flash,
+        LEASE_STORAGE_START,
+        FLASH_SIZE as u32
+    ));
+
+    // add more pools here to serve other subnets through relay agents, or to keep a class of
+    // clients apart. pools with a client class go before the pool for everyone else
+    let routers = [assigned_address];
+    let pools = [PoolConfig {
+        pool: unwrap!(AddressPool::new(
+            Ipv4Address::new(169, 254, 1, 0),
+            24,
+            Ipv4Address::new(169, 254, 1, 2),
+            Ipv4Address::new(169, 254, 1, 11),
+        )),
+        lease_time: Duration::from_secs(60 * 60),
+        routers: &routers,
+        static_routes: &[],
+        dns_server: assigned_address,
+        client_class: None,
+        options: OPTIONS,
+        // to boot clients over the network, serve the boot file from the tftp server and set
+        // `Some(NetworkBoot { server: assigned_address, file: "pxelinux.0" })`
+        network_boot: None,
+    }];
+
+    let config = DhcpConfig {
+        server_address: assigned_address,
+        pools: &pools,
+        classes: CLASSES,
+        offer_time: Duration::from_secs(60),
+        renew_ratio: DEFAULT_RENEW_RATIO,
+        rebind_ratio: DEFAULT_REBIND_RATIO,
+        decline_hold_off: Duration::from_secs(10 * 60),
+        probe_timeout: Some(Duration::from_millis(500)),
+        client_rate_limit: RateLimit {
+            burst: 8,
+            refill_interval: Duration::from_secs(2),
+        },
+        max_packets_per_second: 50,
+        reservations: RESERVATIONS,
+        access: AccessConfig {
+            allow_list: ALLOW_LIST,
+            deny_list: DENY_LIST,
+            ignore_unknown_clients: false,
+        },
+        captive_portal_api: CAPTIVE_PORTAL_API,
+    };
+
+    let mut server: DhcpServer<'_, _, 10, 67, 68, 2048> = unwrap!(DhcpServer::new(
         socket,
-        assigned_address,
-        Duration::from_secs(60 * 60)
+        ConflictProbe::new(probe_socket),
+        config,
+        storage
     ));
 
     server.run().await
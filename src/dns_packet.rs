@@ -1,6 +1,8 @@
 use core::{mem, str::from_utf8};
 use smoltcp::wire::Ipv4Address;
 
+use pico_dhcp_dns_server::lease_table;
+
 use crate::dhcp_server::HOSTNAME;
 
 /// a DNS header is 12 bytes
@@ -104,6 +106,21 @@ impl<'a> DnsQuestion<'a> {
         }
         true
     }
+
+    /// If the name in the question is a single label under `domain`, like `laptop` in
+    /// `laptop.piconet.local`, return that label. The domain is compared ignoring case.
+    pub fn host_label<'b>(buffer: &'b [u8], domain: &str) -> Option<&'b [u8]> {
+        let mut a_iter = AddressIter::new(buffer);
+        let label = a_iter.next()?.ok()?;
+        let mut s_iter = domain.split('.').map(|x| x.as_bytes());
+        loop {
+            match (a_iter.next(), s_iter.next()) {
+                (None, None) => return Some(label),
+                (Some(Ok(a)), Some(s)) if a.eq_ignore_ascii_case(s) => {}
+                _ => return None,
+            }
+        }
+    }
 }
 
 pub struct DnsPacket<'a> {
@@ -184,15 +201,23 @@ impl<'a> DnsPacket<'a> {
         ]);
         // let address_matches = DnsQuestion::matches(&query_buffer[DNS_HEADER_SIZE..], HOSTNAME);
         let address_matches = DnsQuestion::matches(&query_buffer[DNS_HEADER_SIZE..], HOSTNAME);
+        // clients with a lease can be found at `<hostname>.piconet.local`
+        let client_address = DnsQuestion::host_label(&query_buffer[DNS_HEADER_SIZE..], HOSTNAME)
+            .and_then(lease_table::find_by_hostname)
+            .map(|lease| lease.address);
         // copy the address
 
-        query_buffer[ip_address_start..ip_address_start + 4].copy_from_slice(if address_matches {
+        let address = if address_matches {
             log::info!("MATCHED");
-            &primary_ip_address.0
+            primary_ip_address
+        } else if let Some(client_address) = client_address {
+            log::info!("MATCHED CLIENT");
+            client_address
         } else {
             log::info!("DIDNT MATCH");
-            &secondary_ip_address.0
-        });
+            secondary_ip_address
+        };
+        query_buffer[ip_address_start..ip_address_start + 4].copy_from_slice(&address.0);
 
         // this is a response, authoritative
         query_buffer[2] = 0b1000_0001;
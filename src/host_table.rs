@@ -0,0 +1,59 @@
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::lease_table::MAX_LEASES;
+
+/// the longest hostname that is kept, which is the longest a single dns label can be
+pub const MAX_HOSTNAME_LEN: usize = 63;
+
+/// the most hostnames there can be at once, one for each published lease
+pub const MAX_HOSTS: usize = MAX_LEASES;
+
+pub type Hostname = String<MAX_HOSTNAME_LEN>;
+
+/// Turn the name a client sent into a single dns label.
+/// Only the part before the first dot is kept, letters are lowercased and anything other than
+/// letters, digits and hyphens becomes a hyphen. Returns None if nothing usable is left.
+pub fn sanitize(name: &[u8]) -> Option<Hostname> {
+    let label = name.split(|&b| b == b'.').next()?;
+    let mut hostname = Hostname::new();
+    for &b in label.iter().take(MAX_HOSTNAME_LEN) {
+        let c = if b.is_ascii_alphanumeric() {
+            b.to_ascii_lowercase() as char
+        } else {
+            '-'
+        };
+        hostname.push(c).ok()?;
+    }
+    trim_hyphens(&hostname)
+}
+
+/// labels can't start or end with a hyphen
+fn trim_hyphens(name: &str) -> Option<Hostname> {
+    let trimmed = name.trim_matches('-');
+    if trimmed.is_empty() {
+        None
+    } else {
+        Hostname::try_from(trimmed).ok()
+    }
+}
+
+/// Make a hostname unique by adding `-2`, `-3`, ... to the end of it until `is_taken` says
+/// it is free, shortening the name if it needs to make room.
+pub fn deduplicate(name: Hostname, is_taken: impl Fn(&str) -> bool) -> Option<Hostname> {
+    if !is_taken(&name) {
+        return Some(name);
+    }
+    for n in 2..MAX_HOSTS + 2 {
+        let mut suffix: String<8> = String::new();
+        write!(suffix, "-{}", n).ok()?;
+        let base_len = name.len().min(MAX_HOSTNAME_LEN - suffix.len());
+        let mut candidate = trim_hyphens(&name[..base_len])?;
+        candidate.push_str(&suffix).ok()?;
+        if !is_taken(&candidate) {
+            return Some(candidate);
+        }
+    }
+    None
+}
@@ -0,0 +1,451 @@
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Vec;
+
+/// the size of a single snapshot of the lease table in flash
+pub const SLOT_SIZE: usize = 512;
+
+/// the longest client identifier that can be stored with a lease
+pub const MAX_IDENTIFIER_LEN: usize = 32;
+
+/// marks the start of a valid snapshot ("LEAS")
+const MAGIC: u32 = 0x4c45_4153;
+
+/// magic, sequence number, payload length, padding and checksum
+const HEADER_SIZE: usize = 4 + 4 + 2 + 2 + 4;
+
+/// index, remaining lease time and identifier length, followed by the identifier
+const ENTRY_HEADER_SIZE: usize = 2 + 4 + 1;
+
+/// the most leases a snapshot is sure to hold, which is how many fit with the longest
+/// identifiers
+pub const MAX_STORED_LEASES: usize =
+    (SLOT_SIZE - HEADER_SIZE) / (ENTRY_HEADER_SIZE + MAX_IDENTIFIER_LEN);
+
+/// A lease as it is kept in flash. The clock restarts at zero when the pico boots,
+/// so leases are stored with the time they had left when they were written.
+#[derive(Debug, Clone)]
+pub struct StoredLease {
+    /// the index of the lease's address in the pool
+    pub index: u16,
+    pub remaining_secs: u32,
+    pub identifier: Vec<u8, MAX_IDENTIFIER_LEN>,
+}
+
+#[derive(Debug)]
+pub enum LeaseStorageError<E> {
+    Flash(E),
+    /// the leases don't fit in a single snapshot
+    TooLarge,
+}
+
+impl<E> From<E> for LeaseStorageError<E> {
+    fn from(e: E) -> Self {
+        Self::Flash(e)
+    }
+}
+
+/// Stores snapshots of the lease table in a region of flash.
+///
+/// The region is used as a log: every snapshot goes in the slot after the previous one,
+/// wrapping around at the end of the region, so the erase cycles are spread over the whole
+/// region instead of hitting the same sector every time. A sector is only erased when the
+/// log moves into it. On startup, the valid snapshot with the highest sequence number is the
+/// current one, so a snapshot that was cut off by a power loss falls back to the one before.
+pub struct LeaseStorage<F: NorFlash> {
+    flash: F,
+    /// the offset of the start of the region in flash
+    start: u32,
+    n_slots: u32,
+    next_slot: u32,
+    sequence: u32,
+}
+
+impl<F: NorFlash> LeaseStorage<F> {
+    /// Use the flash between the offsets `start` and `end` for storing leases.
+    /// The region has to be made of whole sectors, and needs at least two of them so
+    /// that erasing a sector never erases the latest snapshot.
+    pub fn new(flash: F, start: u32, end: u32) -> Option<Self> {
+        let erase_size = F::ERASE_SIZE as u32;
+        let valid = start % erase_size == 0
+            && end % erase_size == 0
+            && end >= start + 2 * erase_size
+            && end as usize <= flash.capacity()
+            && F::ERASE_SIZE % SLOT_SIZE == 0
+            && SLOT_SIZE % F::WRITE_SIZE == 0
+            && SLOT_SIZE % F::READ_SIZE == 0;
+        valid.then_some(Self {
+            flash,
+            start,
+            n_slots: (end - start) / SLOT_SIZE as u32,
+            next_slot: 0,
+            sequence: 0,
+        })
+    }
+
+    fn slot_offset(&self, slot: u32) -> u32 {
+        self.start + slot * SLOT_SIZE as u32
+    }
+
+    fn starts_sector(&self, slot: u32) -> bool {
+        self.slot_offset(slot) % F::ERASE_SIZE as u32 == 0
+    }
+
+    /// read the slot into the buffer, returning the sequence number and payload length
+    /// if it holds a valid snapshot
+    fn read_slot(
+        &mut self,
+        slot: u32,
+        buffer: &mut [u8; SLOT_SIZE],
+    ) -> Result<Option<(u32, usize)>, F::Error> {
+        self.flash.read(self.slot_offset(slot), buffer)?;
+        let read_u32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        if read_u32(&buffer[0..4]) != MAGIC {
+            return Ok(None);
+        }
+        let sequence = read_u32(&buffer[4..8]);
+        let len = u16::from_le_bytes([buffer[8], buffer[9]]) as usize;
+        if len > SLOT_SIZE - HEADER_SIZE {
+            return Ok(None);
+        }
+        let checksum = read_u32(&buffer[12..16]);
+        let valid = checksum == crc32(&buffer[4..10], &buffer[HEADER_SIZE..HEADER_SIZE + len]);
+        Ok(valid.then_some((sequence, len)))
+    }
+
+    /// Find the latest snapshot and call `restore` with each lease in it.
+    /// This also positions the log, so it should be called before the first `store`.
+    pub fn load(&mut self, mut restore: impl FnMut(StoredLease)) -> Result<(), F::Error> {
+        let mut buffer = [0u8; SLOT_SIZE];
+        let mut latest = None;
+        for slot in 0..self.n_slots {
+            if let Some((sequence, _)) = self.read_slot(slot, &mut buffer)? {
+                match latest {
+                    Some((_, latest_sequence)) if !is_newer(sequence, latest_sequence) => {}
+                    _ => latest = Some((slot, sequence)),
+                }
+            }
+        }
+        let Some((slot, sequence)) = latest else {
+            return Ok(());
+        };
+        self.next_slot = (slot + 1) % self.n_slots;
+        self.sequence = sequence.wrapping_add(1);
+
+        let Some((_, len)) = self.read_slot(slot, &mut buffer)? else {
+            return Ok(());
+        };
+        let mut payload = &buffer[HEADER_SIZE..HEADER_SIZE + len];
+        while payload.len() >= ENTRY_HEADER_SIZE {
+            let index = u16::from_le_bytes([payload[0], payload[1]]);
+            let remaining_secs =
+                u32::from_le_bytes([payload[2], payload[3], payload[4], payload[5]]);
+            let identifier_len = payload[6] as usize;
+            let Some(identifier) =
+                payload.get(ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + identifier_len)
+            else {
+                break;
+            };
+            if let Ok(identifier) = Vec::from_slice(identifier) {
+                restore(StoredLease {
+                    index,
+                    remaining_secs,
+                    identifier,
+                });
+            }
+            payload = &payload[ENTRY_HEADER_SIZE + identifier_len..];
+        }
+        Ok(())
+    }
+
+    /// Write a new snapshot containing these leases.
+    pub fn store(
+        &mut self,
+        leases: impl Iterator<Item = StoredLease>,
+    ) -> Result<(), LeaseStorageError<F::Error>> {
+        let mut buffer = [0xffu8; SLOT_SIZE];
+        let mut len = 0;
+        for lease in leases {
+            let entry_len = ENTRY_HEADER_SIZE + lease.identifier.len();
+            let entry = buffer
+                .get_mut(HEADER_SIZE + len..HEADER_SIZE + len + entry_len)
+                .ok_or(LeaseStorageError::TooLarge)?;
+            entry[0..2].copy_from_slice(&lease.index.to_le_bytes());
+            entry[2..6].copy_from_slice(&lease.remaining_secs.to_le_bytes());
+            entry[6] = lease.identifier.len() as u8;
+            entry[ENTRY_HEADER_SIZE..].copy_from_slice(&lease.identifier);
+            len += entry_len;
+        }
+        buffer[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buffer[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        buffer[8..10].copy_from_slice(&(len as u16).to_le_bytes());
+        let checksum = crc32(&buffer[4..10], &buffer[HEADER_SIZE..HEADER_SIZE + len]);
+        buffer[12..16].copy_from_slice(&checksum.to_le_bytes());
+
+        let mut slot = self.next_slot;
+        if !self.starts_sector(slot) && !self.is_blank(slot)? {
+            // a write to this slot was interrupted, so move on to a fresh sector
+            while !self.starts_sector(slot) {
+                slot = (slot + 1) % self.n_slots;
+            }
+        }
+        if self.starts_sector(slot) {
+            let offset = self.slot_offset(slot);
+            self.flash.erase(offset, offset + F::ERASE_SIZE as u32)?;
+        }
+        self.flash.write(self.slot_offset(slot), &buffer)?;
+
+        self.next_slot = (slot + 1) % self.n_slots;
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    fn is_blank(&mut self, slot: u32) -> Result<bool, F::Error> {
+        let mut buffer = [0u8; SLOT_SIZE];
+        self.flash.read(self.slot_offset(slot), &mut buffer)?;
+        Ok(buffer.iter().all(|&b| b == 0xff))
+    }
+}
+
+/// Whether sequence number `a` came after `b`. The sequence numbers wrap around, so `a` is
+/// newer if it is less than half of the range ahead of `b`.
+fn is_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// the crc-32 (ieee) checksum of the header and payload of a snapshot
+fn crc32(header: &[u8], payload: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in header.iter().chain(payload) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use embedded_storage::nor_flash::{ErrorType, ReadNorFlash};
+
+    const SECTOR_SIZE: usize = 4096;
+    const FLASH_LEN: usize = 4 * SECTOR_SIZE;
+    const N_SLOTS: usize = FLASH_LEN / SLOT_SIZE;
+
+    /// Flash kept in memory, which behaves like nor flash: writes can only clear bits, and
+    /// erasing sets a whole sector back to ones.
+    struct RamFlash {
+        data: [u8; FLASH_LEN],
+        erases: usize,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                data: [0xff; FLASH_LEN],
+                erases: 0,
+            }
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = Infallible;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            FLASH_LEN
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.data[from as usize..to as usize].fill(0xff);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            for (cell, byte) in self.data[offset..offset + bytes.len()]
+                .iter_mut()
+                .zip(bytes)
+            {
+                *cell &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn storage(flash: &mut RamFlash) -> LeaseStorage<&mut RamFlash> {
+        LeaseStorage::new(flash, 0, FLASH_LEN as u32).unwrap()
+    }
+
+    fn lease(index: u16, remaining_secs: u32) -> StoredLease {
+        StoredLease {
+            index,
+            remaining_secs,
+            identifier: Vec::from_slice(&[1, 0x28, 0xcd, 0xc1, 0, 0, index as u8]).unwrap(),
+        }
+    }
+
+    /// store a snapshot with a single lease, whose remaining time tells the snapshots apart
+    fn store(storage: &mut LeaseStorage<&mut RamFlash>, remaining_secs: u32) {
+        storage
+            .store([lease(1, remaining_secs)].into_iter())
+            .unwrap();
+    }
+
+    fn load(flash: &mut RamFlash) -> std::vec::Vec<StoredLease> {
+        let mut leases = std::vec::Vec::new();
+        storage(flash).load(|lease| leases.push(lease)).unwrap();
+        leases
+    }
+
+    /// the remaining time of the only lease in the latest snapshot
+    fn latest(flash: &mut RamFlash) -> Option<u32> {
+        let leases = load(flash);
+        assert!(leases.len() <= 1);
+        leases.first().map(|lease| lease.remaining_secs)
+    }
+
+    #[test]
+    fn leases_are_loaded_as_they_were_stored() {
+        let mut flash = RamFlash::new();
+        let mut storage = storage(&mut flash);
+        storage.load(|_| {}).unwrap();
+        storage
+            .store([lease(0, 60), lease(7, 3600)].into_iter())
+            .unwrap();
+
+        let leases = load(&mut flash);
+        assert_eq!(leases.len(), 2);
+        assert_eq!(leases[0].index, 0);
+        assert_eq!(leases[0].remaining_secs, 60);
+        assert_eq!(leases[1].index, 7);
+        assert_eq!(leases[1].remaining_secs, 3600);
+        assert_eq!(leases[1].identifier, lease(7, 3600).identifier);
+    }
+
+    #[test]
+    fn snapshot_holds_the_most_leases_with_the_longest_identifiers() {
+        let mut flash = RamFlash::new();
+        let mut storage = storage(&mut flash);
+        let lease = |index| StoredLease {
+            index,
+            remaining_secs: 60,
+            identifier: Vec::from_slice(&[index as u8; MAX_IDENTIFIER_LEN]).unwrap(),
+        };
+        let most = MAX_STORED_LEASES as u16;
+        assert!(storage.store((0..most).map(lease)).is_ok());
+        assert!(matches!(
+            storage.store((0..most + 1).map(lease)),
+            Err(LeaseStorageError::TooLarge)
+        ));
+        assert_eq!(load(&mut flash).len(), MAX_STORED_LEASES);
+    }
+
+    #[test]
+    fn empty_flash_has_no_leases() {
+        let mut flash = RamFlash::new();
+        assert!(load(&mut flash).is_empty());
+    }
+
+    #[test]
+    fn log_wraps_around_and_erases_sectors_as_it_enters_them() {
+        let mut flash = RamFlash::new();
+        let mut storage = storage(&mut flash);
+        storage.load(|_| {}).unwrap();
+        let snapshots = N_SLOTS + 3;
+        for remaining_secs in 0..snapshots as u32 {
+            store(&mut storage, remaining_secs);
+        }
+        // one erase for every sector the log moved into, including the first one again
+        let slots_per_sector = SECTOR_SIZE / SLOT_SIZE;
+        assert_eq!(flash.erases, snapshots.div_ceil(slots_per_sector));
+        assert_eq!(latest(&mut flash), Some(snapshots as u32 - 1));
+    }
+
+    #[test]
+    fn log_carries_on_after_a_reboot() {
+        let mut flash = RamFlash::new();
+        let mut storage = storage(&mut flash);
+        storage.load(|_| {}).unwrap();
+        store(&mut storage, 1);
+        store(&mut storage, 2);
+
+        let mut storage = self::storage(&mut flash);
+        storage.load(|_| {}).unwrap();
+        store(&mut storage, 3);
+        assert_eq!(latest(&mut flash), Some(3));
+    }
+
+    #[test]
+    fn snapshot_with_a_bad_checksum_is_skipped() {
+        let mut flash = RamFlash::new();
+        let mut storage = storage(&mut flash);
+        storage.load(|_| {}).unwrap();
+        store(&mut storage, 1);
+        store(&mut storage, 2);
+        // clear a bit in the payload of the second snapshot
+        flash.data[SLOT_SIZE + HEADER_SIZE] &= 0xfe;
+        assert_eq!(latest(&mut flash), Some(1));
+    }
+
+    #[test]
+    fn snapshot_with_a_bad_magic_is_skipped() {
+        let mut flash = RamFlash::new();
+        let mut storage = storage(&mut flash);
+        storage.load(|_| {}).unwrap();
+        store(&mut storage, 1);
+        store(&mut storage, 2);
+        flash.data[SLOT_SIZE] = 0;
+        assert_eq!(latest(&mut flash), Some(1));
+    }
+
+    #[test]
+    fn torn_write_falls_back_to_the_latest_whole_snapshot() {
+        let mut flash = RamFlash::new();
+        let mut storage = storage(&mut flash);
+        storage.load(|_| {}).unwrap();
+        store(&mut storage, 1);
+        store(&mut storage, 2);
+        // a third snapshot that lost power after its header was written, so it has the
+        // highest sequence number but no payload or checksum
+        let torn = 2 * SLOT_SIZE;
+        flash.data[torn..torn + 4].copy_from_slice(&MAGIC.to_le_bytes());
+        flash.data[torn + 4..torn + 8].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(latest(&mut flash), Some(2));
+
+        // the next snapshot goes around the torn slot
+        let mut storage = self::storage(&mut flash);
+        storage.load(|_| {}).unwrap();
+        store(&mut storage, 3);
+        assert_eq!(latest(&mut flash), Some(3));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut flash = RamFlash::new();
+        let mut storage = storage(&mut flash);
+        storage.load(|_| {}).unwrap();
+        storage.sequence = u32::MAX;
+        store(&mut storage, 1);
+        store(&mut storage, 2);
+        assert_eq!(storage.sequence, 1);
+        assert_eq!(latest(&mut flash), Some(2));
+    }
+}
@@ -0,0 +1,69 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::Vec;
+use smoltcp::wire::Ipv4Address;
+
+use crate::client_id::ClientId;
+use crate::host_table::Hostname;
+
+/// the most leases that can be published at once
+pub const MAX_LEASES: usize = 16;
+
+/// A lease as the rest of the firmware sees it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaseInfo {
+    pub address: Ipv4Address,
+    pub identifier: ClientId,
+    pub hostname: Option<Hostname>,
+    /// when the lease ends, or when it ended for leases that are over
+    pub lease_end: Instant,
+}
+
+/// The current leases. The dhcp server publishes these, and any task can read them.
+static LEASES: Mutex<CriticalSectionRawMutex, RefCell<Vec<LeaseInfo, MAX_LEASES>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Replace the published leases with these ones.
+pub fn publish(leases: impl Iterator<Item = LeaseInfo>) {
+    LEASES.lock(|table| {
+        let mut table = table.borrow_mut();
+        table.clear();
+        for lease in leases {
+            if table.push(lease).is_err() {
+                log::warn!("Too many leases to publish");
+                break;
+            }
+        }
+    })
+}
+
+/// Call `f` with the current leases. The table is locked while `f` runs, so it should be quick.
+pub fn with_leases<R>(f: impl FnOnce(&[LeaseInfo]) -> R) -> R {
+    LEASES.lock(|table| f(&table.borrow()))
+}
+
+fn find(matches: impl Fn(&LeaseInfo) -> bool) -> Option<LeaseInfo> {
+    with_leases(|leases| leases.iter().find(|lease| matches(lease)).cloned())
+}
+
+/// the lease on an address
+pub fn find_by_address(address: Ipv4Address) -> Option<LeaseInfo> {
+    find(|lease| lease.address == address)
+}
+
+/// the lease a client has
+pub fn find_by_identifier(identifier: &ClientId) -> Option<LeaseInfo> {
+    find(|lease| lease.identifier == *identifier)
+}
+
+/// the lease of the client with this hostname, ignoring case
+pub fn find_by_hostname(name: &[u8]) -> Option<LeaseInfo> {
+    find(|lease| {
+        lease
+            .hostname
+            .as_ref()
+            .is_some_and(|hostname| hostname.as_bytes().eq_ignore_ascii_case(name))
+    })
+}
@@ -0,0 +1,13 @@
+#![cfg_attr(not(test), no_std)]
+
+pub mod access_control;
+pub mod address_pool;
+pub mod client_class;
+pub mod client_fqdn;
+pub mod client_id;
+pub mod dhcp_leases;
+pub mod dhcp_options;
+pub mod host_table;
+pub mod lease_storage;
+pub mod lease_table;
+pub mod rate_limit;
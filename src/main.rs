@@ -2,10 +2,13 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
+mod captive_portal;
+mod conflict_probe;
 mod dhcp_server;
 mod dns_packet;
 mod dns_server;
 mod network;
+mod tftp_server;
 mod web;
 // mod web;
 
@@ -18,6 +21,7 @@ use embassy_net::{tcp::TcpSocket, Stack};
 use embassy_time::Timer;
 use embedded_io_async::Write;
 use smoltcp::wire::Ipv4Address;
+use tftp_server::tftp_server_task;
 
 use panic_probe as _;
 use web::start_server;
@@ -66,9 +70,10 @@ async fn main(spawner: embassy_executor::Spawner) {
     )
     .await;
 
-    spawner.must_spawn(dhcp_server_task(stack, server_address));
+    spawner.must_spawn(dhcp_server_task(stack, server_address, p.FLASH));
     spawner.must_spawn(dns_server_task(stack, server_address, outside_address));
     spawner.must_spawn(mdns_server_task(stack, server_address, outside_address));    
+    spawner.must_spawn(tftp_server_task(stack));
     start_server(&spawner, stack).await;
     spawner.must_spawn(alive());
 }
@@ -0,0 +1,159 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/// How many messages a client can send
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// the most messages a client can send in a burst
+    pub burst: u32,
+    /// how long it takes for a client to be able to send one more message
+    pub refill_interval: Duration,
+}
+
+/// A token bucket: every message takes a token, and tokens come back one per refill interval
+/// up to the burst size.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: u32,
+    /// when the last token came back, or when the bucket was last full
+    refilled_at: Instant,
+    /// when the client last sent a message, whether or not it was let through
+    last_seen: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            refilled_at: now,
+            last_seen: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let interval = limit.refill_interval.as_ticks().max(1);
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_ticks();
+        let refilled = (elapsed / interval).min(limit.burst as u64) as u32;
+        self.tokens = (self.tokens + refilled).min(limit.burst);
+        if self.tokens == limit.burst {
+            self.refilled_at = now;
+        } else {
+            self.refilled_at += Duration::from_ticks(refilled as u64 * interval);
+        }
+    }
+
+    fn try_take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.last_seen = now;
+        self.refill(limit, now);
+        if self.tokens == 0 {
+            false
+        } else {
+            self.tokens -= 1;
+            true
+        }
+    }
+}
+
+/// Keeps a token bucket for each of the clients that have sent messages recently.
+/// When there's no room for another client, the one that has been quiet for longest is
+/// forgotten.
+pub struct ClientRateLimiter<K, const N: usize> {
+    limit: RateLimit,
+    buckets: Vec<(K, TokenBucket), N>,
+}
+
+impl<K: PartialEq, const N: usize> ClientRateLimiter<K, N> {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Vec::new(),
+        }
+    }
+
+    /// Take a token for a message from the client. Returns false if the client is over its
+    /// limit and the message should be dropped.
+    pub fn try_take(&mut self, client: K, now: Instant) -> bool {
+        let index = match self.buckets.iter().position(|(k, _)| *k == client) {
+            Some(index) => index,
+            None => {
+                if self.buckets.is_full() {
+                    if let Some(quietest) = self
+                        .buckets
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, (_, bucket))| bucket.last_seen)
+                        .map(|(i, _)| i)
+                    {
+                        self.buckets.swap_remove(quietest);
+                    }
+                }
+                if self
+                    .buckets
+                    .push((client, TokenBucket::new(&self.limit, now)))
+                    .is_err()
+                {
+                    return true;
+                }
+                self.buckets.len() - 1
+            }
+        };
+        self.buckets[index].1.try_take(&self.limit, now)
+    }
+}
+
+/// Counts the messages in each second, to keep the server under a ceiling no matter how many
+/// clients are sending.
+pub struct PacketCeiling {
+    max_per_second: u32,
+    second_start: Instant,
+    count: u32,
+}
+
+impl PacketCeiling {
+    pub fn new(max_per_second: u32) -> Self {
+        Self {
+            max_per_second,
+            second_start: Instant::from_ticks(0),
+            count: 0,
+        }
+    }
+
+    /// Count a message. Returns false if there have already been too many this second and
+    /// the message should be dropped.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.second_start) >= Duration::from_secs(1) {
+            self.second_start = now;
+            self.count = 0;
+        }
+        if self.count >= self.max_per_second {
+            false
+        } else {
+            self.count += 1;
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        burst: 1,
+        refill_interval: Duration::from_secs(60),
+    };
+
+    #[test]
+    fn quietest_client_is_forgotten() {
+        let start = Instant::from_secs(100);
+        let mut limiter: ClientRateLimiter<u8, 2> = ClientRateLimiter::new(LIMIT);
+        assert!(limiter.try_take(1, start));
+        assert!(limiter.try_take(2, start + Duration::from_secs(1)));
+        // the first client keeps sending, and stays over its limit with its bucket empty
+        assert!(!limiter.try_take(1, start + Duration::from_secs(2)));
+        // so a new client pushes out the second one, which has been quiet for longer
+        assert!(limiter.try_take(3, start + Duration::from_secs(3)));
+        assert!(!limiter.try_take(1, start + Duration::from_secs(4)));
+        assert!(limiter.try_take(2, start + Duration::from_secs(5)));
+    }
+}
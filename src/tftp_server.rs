@@ -0,0 +1,448 @@
+use core::fmt::Write;
+
+use defmt::unwrap;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_net::udp::{PacketMetadata, SendError, UdpSocket};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
+use smoltcp::wire::IpEndpoint;
+
+/// A file the tftp server hands out
+pub struct TftpFile {
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+/// the files served over tftp, which are built into the firmware.
+/// add entries here to serve a file, for example
+/// `TftpFile { name: "pxelinux.0", data: include_bytes!("../boot/pxelinux.0") }`
+const FILES: &[TftpFile] = &[];
+
+const OPCODE_READ_REQUEST: u16 = 1;
+const OPCODE_WRITE_REQUEST: u16 = 2;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+/// the option acknowledgement from RFC 2347
+const OPCODE_OPTION_ACK: u16 = 6;
+
+const ERROR_NOT_DEFINED: u16 = 0;
+const ERROR_FILE_NOT_FOUND: u16 = 1;
+const ERROR_ACCESS_VIOLATION: u16 = 2;
+const ERROR_ILLEGAL_OPERATION: u16 = 4;
+const ERROR_UNKNOWN_TRANSFER_ID: u16 = 5;
+
+/// the block size option from RFC 2348
+const OPTION_BLOCK_SIZE: &[u8] = b"blksize";
+
+/// the block size used when the client doesn't ask for one
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// the smallest block size a client can ask for, from RFC 2348
+const MIN_BLOCK_SIZE: usize = 8;
+
+/// the opcode and block number in front of the data in a data packet
+const DATA_HEADER_LEN: usize = 4;
+
+/// the most transfers that can run at once
+const MAX_TRANSFERS: usize = 4;
+
+/// the longest packet the server sends or receives, which is a data packet of the biggest block
+const DATA_BUFFER_LEN: usize = 1028;
+
+/// the biggest block that fits in the data buffer
+const MAX_BLOCK_SIZE: usize = DATA_BUFFER_LEN - DATA_HEADER_LEN;
+
+/// how long to wait for an acknowledgement before sending a block again
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// how many times a block is sent again before the transfer is given up on
+const MAX_RETRIES: u8 = 5;
+
+type NetStack = embassy_net::Stack<cyw43::NetDriver<'static>>;
+
+async fn send_error(
+    socket: &mut UdpSocket<'_>,
+    buffer: &mut [u8],
+    client: IpEndpoint,
+    code: u16,
+    message: &str,
+) -> Result<(), SendError> {
+    let len = 4 + message.len() + 1;
+    let Some(packet) = buffer.get_mut(..len) else {
+        return Ok(());
+    };
+    packet[0..2].copy_from_slice(&OPCODE_ERROR.to_be_bytes());
+    packet[2..4].copy_from_slice(&code.to_be_bytes());
+    packet[4..len - 1].copy_from_slice(message.as_bytes());
+    packet[len - 1] = 0;
+    socket.send_to(packet, client).await
+}
+
+/// A file being sent to a client.
+/// Every transfer has its own socket on a port picked for it, and only talks to the client's
+/// endpoint: the two ports are the transfer ids from RFC 1350.
+struct Transfer<'a> {
+    socket: UdpSocket<'a>,
+    data_buffer: [u8; DATA_BUFFER_LEN],
+    client: IpEndpoint,
+    data: &'static [u8],
+    block_size: usize,
+    /// the number of the last block sent, where 0 is the option acknowledgement
+    block: usize,
+    sent_at: Instant,
+    retries: u8,
+}
+
+impl Transfer<'_> {
+    /// the data in a block, which is shorter than the block size for the last block
+    fn block_data(&self, block: usize) -> &'static [u8] {
+        let start = (block - 1) * self.block_size;
+        let end = (start + self.block_size).min(self.data.len());
+        self.data.get(start..end).unwrap_or(&[])
+    }
+
+    /// whether the last block sent was the end of the file
+    fn is_finished(&self) -> bool {
+        self.block > 0 && self.block_data(self.block).len() < self.block_size
+    }
+
+    /// Send the current block, or the option acknowledgement if no blocks have been sent yet.
+    async fn send_block(&mut self) -> Result<(), SendError> {
+        let len = if self.block == 0 {
+            let mut block_size: String<8> = String::new();
+            let _ = write!(block_size, "{}", self.block_size);
+            let len = 2 + OPTION_BLOCK_SIZE.len() + 1 + block_size.len() + 1;
+            let packet = &mut self.data_buffer[..len];
+            packet[0..2].copy_from_slice(&OPCODE_OPTION_ACK.to_be_bytes());
+            let (name, value) = packet[2..].split_at_mut(OPTION_BLOCK_SIZE.len() + 1);
+            name[..OPTION_BLOCK_SIZE.len()].copy_from_slice(OPTION_BLOCK_SIZE);
+            name[OPTION_BLOCK_SIZE.len()] = 0;
+            value[..block_size.len()].copy_from_slice(block_size.as_bytes());
+            value[block_size.len()] = 0;
+            len
+        } else {
+            let data = self.block_data(self.block);
+            let len = DATA_HEADER_LEN + data.len();
+            let packet = &mut self.data_buffer[..len];
+            packet[0..2].copy_from_slice(&OPCODE_DATA.to_be_bytes());
+            // block numbers wrap around for files with more than 65535 blocks
+            packet[2..4].copy_from_slice(&(self.block as u16).to_be_bytes());
+            packet[DATA_HEADER_LEN..].copy_from_slice(data);
+            len
+        };
+        self.sent_at = Instant::now();
+        self.socket
+            .send_to(&self.data_buffer[..len], self.client)
+            .await
+    }
+
+    /// Move on to the next block once the client has acknowledged the current one, returning
+    /// whether the transfer is over. Acknowledgements for any other block are ignored, rather
+    /// than sending the current block again, so that a delayed acknowledgement doesn't double
+    /// up every block after it.
+    async fn process_ack(&mut self, len: usize) -> Result<bool, SendError> {
+        if len < 4 {
+            return Ok(false);
+        }
+        let block = u16::from_be_bytes([self.data_buffer[2], self.data_buffer[3]]);
+        if block != self.block as u16 {
+            return Ok(false);
+        }
+        if self.is_finished() {
+            log::info!("Finished a tftp transfer to {:?}", self.client);
+            return Ok(true);
+        }
+        self.block += 1;
+        self.retries = 0;
+        self.send_block().await.map(|()| false)
+    }
+
+    /// handle a packet from the client, returning whether the transfer is over
+    async fn process_packet(&mut self, len: usize) -> Result<bool, SendError> {
+        if len < 2 {
+            return Ok(false);
+        }
+        match u16::from_be_bytes([self.data_buffer[0], self.data_buffer[1]]) {
+            OPCODE_ACK => self.process_ack(len).await,
+            // the client gave up on the transfer
+            OPCODE_ERROR => Ok(true),
+            _ => {
+                let client = self.client;
+                send_error(
+                    &mut self.socket,
+                    &mut self.data_buffer,
+                    client,
+                    ERROR_ILLEGAL_OPERATION,
+                    "Unexpected packet",
+                )
+                .await
+                .map(|()| false)
+            }
+        }
+    }
+
+    /// Send the file, sending blocks again when they aren't acknowledged in time, until the
+    /// client has all of it or the transfer runs out of retries.
+    async fn run(&mut self) {
+        if self.send_block().await.is_err() {
+            log::warn!("Error sending a tftp packet");
+        }
+        loop {
+            let result = match select(
+                self.socket.recv_from(&mut self.data_buffer),
+                Timer::at(self.sent_at + RETRANSMIT_TIMEOUT),
+            )
+            .await
+            {
+                Either::First(Ok((len, endpoint))) if endpoint == self.client => {
+                    match self.process_packet(len).await {
+                        Ok(true) => return,
+                        result => result.map(|_| ()),
+                    }
+                }
+                // a packet from anywhere else gets an error, without ending the transfer
+                Either::First(Ok((_, endpoint))) => {
+                    send_error(
+                        &mut self.socket,
+                        &mut self.data_buffer,
+                        endpoint,
+                        ERROR_UNKNOWN_TRANSFER_ID,
+                        "Unknown transfer",
+                    )
+                    .await
+                }
+                Either::First(Err(_)) => {
+                    log::info!("Error receiving data");
+                    Ok(())
+                }
+                Either::Second(()) => {
+                    if self.retries >= MAX_RETRIES {
+                        log::warn!("Tftp transfer to {:?} timed out", self.client);
+                        return;
+                    }
+                    self.retries += 1;
+                    self.send_block().await
+                }
+            };
+            if result.is_err() {
+                log::warn!("Error sending a tftp packet");
+            }
+        }
+    }
+}
+
+/// Send `data` to `client` from a new socket, starting with an option acknowledgement if the
+/// client asked for a block size.
+#[embassy_executor::task(pool_size = MAX_TRANSFERS)]
+async fn tftp_transfer_task(
+    stack: &'static NetStack,
+    client: IpEndpoint,
+    data: &'static [u8],
+    block_size: Option<usize>,
+) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; DATA_BUFFER_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 2 * DATA_BUFFER_LEN];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // binding to port 0 picks a free port, which is the server's transfer id
+    if socket.bind(0).is_err() {
+        log::warn!("Couldn't bind a socket for a tftp transfer");
+        return;
+    }
+    let mut transfer = Transfer {
+        socket,
+        data_buffer: [0; DATA_BUFFER_LEN],
+        client,
+        data,
+        block_size: block_size.unwrap_or(DEFAULT_BLOCK_SIZE),
+        block: if block_size.is_some() { 0 } else { 1 },
+        sent_at: Instant::now(),
+        retries: 0,
+    };
+    transfer.run().await
+}
+
+/// The parts of a read request the server uses
+struct ReadRequest<'p> {
+    filename: &'p [u8],
+    mode: &'p [u8],
+    block_size: Option<usize>,
+}
+
+impl<'p> ReadRequest<'p> {
+    /// Parse the body of a request: the filename, the mode, then any options, each of them
+    /// ending in a zero byte. Options the server doesn't know are skipped.
+    fn parse(body: &'p [u8]) -> Option<Self> {
+        let mut fields = body.strip_suffix(&[0])?.split(|&b| b == 0);
+        let filename = fields.next()?;
+        let mode = fields.next()?;
+        let mut block_size = None;
+        while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+            if name.eq_ignore_ascii_case(OPTION_BLOCK_SIZE) {
+                block_size = core::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.parse().ok());
+            }
+        }
+        Some(Self {
+            filename,
+            mode,
+            block_size,
+        })
+    }
+}
+
+/// A read-only tftp server (RFC 1350) for the files in `FILES`, which can negotiate the
+/// block size (RFC 2348). The server's port only takes read requests, and each transfer is
+/// sent from its own port by a `tftp_transfer_task`.
+struct TftpServer<'a, const SERVER_PORT: u16> {
+    stack: &'static NetStack,
+    spawner: Spawner,
+    socket: UdpSocket<'a>,
+    data_buffer: [u8; DATA_BUFFER_LEN],
+}
+
+impl<'a, const SERVER_PORT: u16> TftpServer<'a, SERVER_PORT> {
+    fn new(stack: &'static NetStack, spawner: Spawner, mut socket: UdpSocket<'a>) -> Option<Self> {
+        if socket.endpoint().is_specified() {
+            None
+        } else {
+            socket.bind(SERVER_PORT).ok()?;
+            Some(Self {
+                stack,
+                spawner,
+                socket,
+                data_buffer: [0; DATA_BUFFER_LEN],
+            })
+        }
+    }
+
+    async fn send_error(
+        &mut self,
+        client: IpEndpoint,
+        code: u16,
+        message: &str,
+    ) -> Result<(), SendError> {
+        send_error(
+            &mut self.socket,
+            &mut self.data_buffer,
+            client,
+            code,
+            message,
+        )
+        .await
+    }
+
+    /// Start sending a file to a client from a new transfer task.
+    async fn process_read_request(
+        &mut self,
+        client: IpEndpoint,
+        len: usize,
+    ) -> Result<(), SendError> {
+        let Some(request) = ReadRequest::parse(&self.data_buffer[2..len]) else {
+            return self
+                .send_error(client, ERROR_ILLEGAL_OPERATION, "Bad request")
+                .await;
+        };
+        if !request.mode.eq_ignore_ascii_case(b"octet") {
+            return self
+                .send_error(
+                    client,
+                    ERROR_ILLEGAL_OPERATION,
+                    "Only octet mode is supported",
+                )
+                .await;
+        }
+        let block_size = request
+            .block_size
+            .map(|size| size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE));
+        let Some(file) = FILES
+            .iter()
+            .find(|file| file.name.as_bytes() == request.filename)
+        else {
+            return self
+                .send_error(client, ERROR_FILE_NOT_FOUND, "File not found")
+                .await;
+        };
+        log::info!("Sending {} over tftp", file.name);
+
+        if self
+            .spawner
+            .spawn(tftp_transfer_task(
+                self.stack, client, file.data, block_size,
+            ))
+            .is_err()
+        {
+            return self
+                .send_error(client, ERROR_NOT_DEFINED, "Too many transfers")
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn process_packet(&mut self, client: IpEndpoint, len: usize) -> Result<(), SendError> {
+        if len < 2 {
+            return Ok(());
+        }
+        match u16::from_be_bytes([self.data_buffer[0], self.data_buffer[1]]) {
+            OPCODE_READ_REQUEST => self.process_read_request(client, len).await,
+            OPCODE_WRITE_REQUEST => {
+                self.send_error(client, ERROR_ACCESS_VIOLATION, "The server is read only")
+                    .await
+            }
+            // errors don't get answered
+            OPCODE_ERROR => Ok(()),
+            // everything else belongs to a transfer, which has its own port
+            _ => {
+                self.send_error(client, ERROR_UNKNOWN_TRANSFER_ID, "Unknown transfer")
+                    .await
+            }
+        }
+    }
+
+    async fn run(&mut self) -> ! {
+        loop {
+            let result = match self.socket.recv_from(&mut self.data_buffer).await {
+                Ok((len, client)) => self.process_packet(client, len).await,
+                Err(_) => {
+                    log::info!("Error receiving data");
+                    Ok(())
+                }
+            };
+            if result.is_err() {
+                log::warn!("Error sending a tftp packet");
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn tftp_server_task(stack: &'static NetStack) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 16];
+    let mut rx_buffer = [0; 2048];
+    let mut tx_meta = [PacketMetadata::EMPTY; 16];
+    let mut tx_buffer = [0; 1024];
+
+    let socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let spawner = Spawner::for_current_executor().await;
+    let mut server: TftpServer<'_, 69> = unwrap!(TftpServer::new(stack, spawner, socket));
+    log::info!("Running the tftp server");
+    server.run().await
+}
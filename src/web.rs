@@ -1,6 +1,6 @@
 use cyw43::NetDriver;
 use embassy_executor::Spawner;
-use embassy_net::Stack;
+use embassy_net::{IpAddress, Stack};
 use embassy_time::Duration;
 use picoserve::{
     response::{
@@ -9,8 +9,11 @@ use picoserve::{
     routing::{get, Layer, PathRouter},
     ResponseSent, Router,
 };
+use smoltcp::wire::Ipv4Address;
 use static_cell::make_static;
 
+use crate::captive_portal;
+
 pub const WEB_TASK_POOL_SIZE: usize = 3;
 
 struct EmbassyTimer;
@@ -28,13 +31,20 @@ impl picoserve::Timer for EmbassyTimer {
     }
 }
 
-type AppRouter = impl PathRouter;
+/// what the app knows about the connection it is answering
+struct Connection {
+    client: Option<Ipv4Address>,
+    /// the address the client reached the server on
+    server: Option<Ipv4Address>,
+}
+
+type AppRouter = impl PathRouter<Connection>;
 
 #[embassy_executor::task(pool_size = WEB_TASK_POOL_SIZE)]
 async fn web_task(
     id: usize,
     stack: &'static embassy_net::Stack<cyw43::NetDriver<'static>>,
-    app: &'static picoserve::Router<AppRouter>,
+    app: &'static picoserve::Router<AppRouter, Connection>,
     config: &'static picoserve::Config<Duration>,
 ) -> ! {
     let mut rx_buffer = [0; 1024];
@@ -54,14 +64,29 @@ async fn web_task(
             socket.remote_endpoint()
         );
 
+        let connection = Connection {
+            client: match socket.remote_endpoint() {
+                Some(endpoint) => match endpoint.addr {
+                    IpAddress::Ipv4(address) => Some(address),
+                },
+                None => None,
+            },
+            server: match socket.local_endpoint() {
+                Some(endpoint) => match endpoint.addr {
+                    IpAddress::Ipv4(address) => Some(address),
+                },
+                None => None,
+            },
+        };
         let (socket_rx, socket_tx) = socket.split();
-        match picoserve::serve(
+        match picoserve::serve_with_state(
             app,
             EmbassyTimer,
             config,
             &mut [0; 2048],
             socket_rx,
             socket_tx,
+            &connection,
         )
         .await
         {
@@ -78,8 +103,8 @@ async fn web_task(
 
 struct S;
 
-impl<State, PathParameters> Layer<State, PathParameters> for S {
-    type NextState = State;
+impl<PathParameters> Layer<Connection, PathParameters> for S {
+    type NextState = Connection;
 
     type NextPathParameters = PathParameters;
 
@@ -89,7 +114,7 @@ impl<State, PathParameters> Layer<State, PathParameters> for S {
     >(
         &self,
         next: NextLayer,
-        state: &State,
+        state: &Connection,
         path_parameters: PathParameters,
         request: picoserve::request::Request<'_>,
         response_writer: W,
@@ -99,6 +124,19 @@ impl<State, PathParameters> Layer<State, PathParameters> for S {
             .get("Host")
             .map_or(false, |h| h == "169.254.1.1")
         {
+            // the captive portal api from RFC 8908
+            if let (captive_portal::API_PATH, Some(server)) =
+                (request.path().encoded(), state.server)
+            {
+                let body = captive_portal::api_response(state.client, server);
+                return response_writer
+                    .write_response(
+                        Response::new(StatusCode::OK, body.as_str())
+                            .with_header("Content-Type", captive_portal::API_CONTENT_TYPE)
+                            .with_header("Cache-Control", "private"),
+                    )
+                    .await;
+            }
             response_writer
                 .write_response(Json("hi").into_response())
                 .await
@@ -107,7 +145,7 @@ impl<State, PathParameters> Layer<State, PathParameters> for S {
         }
     }
 }
-fn make_app() -> picoserve::Router<AppRouter> {
+fn make_app() -> picoserve::Router<AppRouter, Connection> {
     Router::new().layer(S)
 }
 